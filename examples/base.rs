@@ -13,7 +13,7 @@ async fn main() -> anyhow::Result<()> {
         .temperature(0.7)
         .build()?;
 
-    let completion = llm.complete_chat(prompt).await?;
+    let completion = llm.complete_chat(&prompt).await?;
 
     for message in completion.messages {
         println!("{:?} :::: {}", message.role, message.content);
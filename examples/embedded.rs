@@ -0,0 +1,18 @@
+use promptpunch::prelude::*;
+
+promptpunch::prompt!(Summarize, "examples/prompts/summarize.md");
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let llm = ChatGpt::from_env();
+    let prompt = Summarize {
+        document: "Rust is a general-purpose programming language emphasizing performance, type safety, and concurrency.".to_string(),
+        words: Some(10),
+    }
+    .prompt()?;
+
+    let completion = llm.complete_chat(&prompt).await?;
+    println!("{}", completion.last_assistant_response()?);
+
+    Ok(())
+}
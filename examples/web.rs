@@ -2,6 +2,7 @@ use promptpunch::{
     llm::chat_gpt::ChatGpt,
     web::{AppState, PromptInfo},
 };
+use std::sync::Arc;
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
 
 #[tokio::main]
@@ -16,7 +17,7 @@ async fn main() {
         .init();
 
     let state = AppState {
-        llm: ChatGpt::from_env(),
+        llm: Arc::new(ChatGpt::from_env()),
         prompt_info: PromptInfo::default(),
     };
     let router = promptpunch::web::init_router(state);
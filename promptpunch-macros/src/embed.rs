@@ -0,0 +1,162 @@
+use proc_macro2::{Span, TokenStream};
+use promptpunch_markdown::InputType;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    Ident, LitStr, Token, Visibility,
+};
+
+use crate::markdown::{
+    check_body, find_placeholders, has_default, read_prompt, split_front_matter,
+};
+
+pub struct PromptMacroInput {
+    item: Option<(Visibility, Ident)>,
+    path: LitStr,
+}
+
+impl Parse for PromptMacroInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(LitStr) {
+            return Ok(Self {
+                item: None,
+                path: input.parse()?,
+            });
+        }
+        let visibility = input.parse()?;
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        Ok(Self {
+            item: Some((visibility, name)),
+            path: input.parse()?,
+        })
+    }
+}
+
+struct Field {
+    placeholder: String,
+    ident: Ident,
+    kind: InputType,
+    optional: bool,
+}
+
+pub fn expand(input: PromptMacroInput) -> syn::Result<TokenStream> {
+    let span = input.path.span();
+    let error = |message: String| syn::Error::new(span, message);
+
+    let (path, markdown) = read_prompt(&input.path)?;
+    let (inputs, body) = split_front_matter(&markdown).map_err(error)?;
+    check_body(body).map_err(error)?;
+
+    let mut fields: Vec<Field> = vec![];
+    for placeholder in find_placeholders(body) {
+        if fields.iter().any(|field| field.placeholder == placeholder) {
+            continue;
+        }
+        let name = &placeholder[1..placeholder.len() - 1];
+        let ident = field_ident(name).map_err(error)?;
+        if fields.iter().any(|field| field.ident == ident) {
+            return Err(error(format!(
+                "Placeholder {placeholder} maps to the same field as another placeholder"
+            )));
+        }
+        fields.push(Field {
+            kind: inputs.get(name).map(|spec| spec.kind).unwrap_or_default(),
+            optional: has_default(&inputs, name),
+            placeholder,
+            ident,
+        });
+    }
+
+    let Some((visibility, name)) = input.item else {
+        if let Some(field) = fields.iter().find(|field| !field.optional) {
+            return Err(error(format!(
+                "Prompt requires {} placeholder, use prompt!(Name, \"path\") to generate a struct with the inputs",
+                field.placeholder
+            )));
+        }
+        return Ok(quote! {
+            ::promptpunch::prompt::parse_markdown_prompt(::core::include_str!(#path), &[])
+        });
+    };
+
+    let definitions = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let ty = match field.kind {
+            InputType::String => quote! { ::std::string::String },
+            InputType::Integer => quote! { i64 },
+            InputType::Number => quote! { f64 },
+            InputType::Boolean => quote! { bool },
+            InputType::Json => quote! { ::promptpunch::__private::serde_json::Value },
+        };
+        match field.optional {
+            true => quote! { pub #ident: ::core::option::Option<#ty> },
+            false => quote! { pub #ident: #ty },
+        }
+    });
+    let injections = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let placeholder = &field.placeholder;
+        let content = match field.kind {
+            InputType::Json => {
+                quote! { ::promptpunch::__private::serde_json::to_string_pretty(value)? }
+            }
+            _ => quote! { value },
+        };
+        let push = quote! {
+            data.push(::promptpunch::prompt::InjectableData::new(#placeholder, #content));
+        };
+        match field.optional {
+            true => quote! {
+                if let ::core::option::Option::Some(value) = &self.#ident {
+                    #push
+                }
+            },
+            false => quote! {
+                let value = &self.#ident;
+                #push
+            },
+        }
+    });
+
+    Ok(quote! {
+        #[derive(Debug, Clone, Default)]
+        #visibility struct #name {
+            #(#definitions,)*
+        }
+
+        impl #name {
+            /// Prompt markdown embedded at compile time
+            pub const MARKDOWN: &'static str = ::core::include_str!(#path);
+
+            pub fn prompt(
+                &self,
+            ) -> ::core::result::Result<::promptpunch::Prompt, ::promptpunch::__private::anyhow::Error>
+            {
+                #[allow(unused_mut)]
+                let mut data = ::std::vec::Vec::new();
+                #(#injections)*
+                ::promptpunch::prompt::parse_markdown_prompt(Self::MARKDOWN, &data)
+            }
+        }
+    })
+}
+
+fn field_ident(name: &str) -> Result<Ident, String> {
+    let normalized = name.replace(['-', '.'], "_");
+    if syn::parse_str::<Ident>(&normalized).is_ok() {
+        return Ok(Ident::new(&normalized, Span::call_site()));
+    }
+    let starts_with_digit = normalized.starts_with(|c: char| c.is_ascii_digit());
+    if starts_with_digit
+        || matches!(
+            normalized.as_str(),
+            "_" | "self" | "Self" | "super" | "crate"
+        )
+    {
+        return Err(format!(
+            "Placeholder {{{name}}} can't be used as a field name"
+        ));
+    }
+    Ok(Ident::new_raw(&normalized, Span::call_site()))
+}
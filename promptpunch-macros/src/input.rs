@@ -0,0 +1,187 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, LitStr, Type};
+
+use crate::markdown::{find_placeholders, has_default, read_prompt, split_front_matter};
+
+enum Format {
+    Display,
+    Json,
+}
+
+struct InputField {
+    ident: syn::Ident,
+    placeholder: String,
+    format: Format,
+    optional: bool,
+}
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "PromptInput can only be derived for structs",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "PromptInput requires named fields",
+        ));
+    };
+
+    let mut prompt_path: Option<LitStr> = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("prompt"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("path") {
+                prompt_path = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `path = \"...\"`"))
+            }
+        })?;
+    }
+
+    let mut fields = vec![];
+    for field in &named.named {
+        let ident = field.ident.clone().expect("Named field has ident");
+        let mut placeholder = ident.to_string().trim_start_matches("r#").to_string();
+        let mut format = Format::Display;
+        let mut skip = false;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("prompt"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("json") {
+                    format = Format::Json;
+                } else if meta.path.is_ident("skip") {
+                    skip = true;
+                } else if meta.path.is_ident("rename") {
+                    placeholder = meta.value()?.parse::<LitStr>()?.value();
+                } else {
+                    return Err(meta.error("expected `json`, `skip` or `rename = \"...\"`"));
+                }
+                Ok(())
+            })?;
+        }
+        if !skip {
+            fields.push(InputField {
+                ident,
+                placeholder: format!("{{{placeholder}}}"),
+                format,
+                optional: is_option(&field.ty),
+            });
+        }
+    }
+
+    // Rebuilds the input when the checked prompt changes
+    let track_prompt = match &prompt_path {
+        Some(path) => {
+            let absolute = check_prompt(path, &fields)?;
+            quote! { const _: &str = ::core::include_str!(#absolute); }
+        }
+        None => quote! {},
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let placeholders = fields.iter().map(|field| &field.placeholder);
+    let injections = fields.iter().map(|field| {
+        let ident = &field.ident;
+        let placeholder = &field.placeholder;
+        let value = quote! { value };
+        let content = match field.format {
+            Format::Display => quote! { #value },
+            Format::Json => {
+                quote! { ::promptpunch::__private::serde_json::to_string_pretty(#value)? }
+            }
+        };
+        let push = quote! {
+            data.push(::promptpunch::prompt::InjectableData::new(#placeholder, #content));
+        };
+        match field.optional {
+            true => quote! {
+                if let ::core::option::Option::Some(#value) = &self.#ident {
+                    #push
+                }
+            },
+            false => quote! {
+                let #value = &self.#ident;
+                #push
+            },
+        }
+    });
+
+    Ok(quote! {
+        #track_prompt
+
+        impl #impl_generics ::promptpunch::prompt::PromptInput for #name #ty_generics #where_clause {
+            fn placeholders() -> ::std::vec::Vec<::std::string::String> {
+                ::std::vec![#(::std::string::String::from(#placeholders)),*]
+            }
+
+            fn injectable_data(
+                &self,
+            ) -> ::core::result::Result<
+                ::std::vec::Vec<::promptpunch::prompt::InjectableData>,
+                ::promptpunch::__private::anyhow::Error,
+            > {
+                #[allow(unused_mut)]
+                let mut data = ::std::vec::Vec::new();
+                #(#injections)*
+                ::core::result::Result::Ok(data)
+            }
+        }
+    })
+}
+
+/// Every field must match a placeholder and every required placeholder must have a field
+fn check_prompt(path: &LitStr, fields: &[InputField]) -> syn::Result<String> {
+    let (absolute, markdown) = read_prompt(path)?;
+    let (inputs, body) =
+        split_front_matter(&markdown).map_err(|err| syn::Error::new(path.span(), err))?;
+    let placeholders = find_placeholders(body);
+
+    for field in fields {
+        if !placeholders.contains(&field.placeholder) {
+            return Err(syn::Error::new_spanned(
+                &field.ident,
+                format!(
+                    "Placeholder {} is not used in {}",
+                    field.placeholder,
+                    path.value()
+                ),
+            ));
+        }
+    }
+    for placeholder in &placeholders {
+        let name = &placeholder[1..placeholder.len() - 1];
+        if !has_default(&inputs, name)
+            && !fields.iter().any(|field| &field.placeholder == placeholder)
+        {
+            return Err(syn::Error::new(
+                path.span(),
+                format!("Placeholder {placeholder} has no matching field"),
+            ));
+        }
+    }
+    Ok(absolute)
+}
+
+fn is_option(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    path.qself.is_none()
+        && path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option")
+}
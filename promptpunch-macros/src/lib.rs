@@ -0,0 +1,47 @@
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput};
+
+mod embed;
+mod input;
+mod markdown;
+
+/// Embeds markdown prompt at compile time.
+///
+/// The path is relative to the crate root, parse errors and placeholder
+/// problems fail the build.
+///
+/// `prompt!("prompts/greet.md")` evaluates to `anyhow::Result<Prompt>`
+/// and is only allowed for prompts without required placeholders.
+///
+/// `prompt!(pub Summarize, "prompts/summarize.md")` defines a struct
+/// with a field per placeholder and a `prompt(&self)` method.
+/// Fields are typed by the front matter input type, `String` for `string` and
+/// undeclared inputs, `i64`, `f64`, `bool` or `serde_json::Value` for `integer`,
+/// `number`, `boolean` or `json`, wrapped in `Option` when the input has a default.
+#[proc_macro]
+pub fn prompt(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as embed::PromptMacroInput);
+    match embed::expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Implements `PromptInput`, every field is injected into the `{field}` placeholder.
+///
+/// Field attributes:
+/// - `#[prompt(rename = "name")]` injects into `{name}`
+/// - `#[prompt(json)]` formats the value as pretty JSON instead of `Display`
+/// - `#[prompt(skip)]` ignores the field
+///
+/// `Option` fields are injected only when set.
+/// `#[prompt(path = "prompts/summarize.md")]` on the struct checks at compile time
+/// that the fields match the placeholders of the prompt.
+#[proc_macro_derive(PromptInput, attributes(prompt))]
+pub fn derive_prompt_input(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match input::expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
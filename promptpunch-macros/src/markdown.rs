@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use promptpunch_markdown::{parse_include, parse_role_header, InputSpec};
+use syn::LitStr;
+
+/// Reads prompt file relative to the crate root,
+/// returns the absolute path for `include_str!` and the content
+pub fn read_prompt(path: &LitStr) -> syn::Result<(String, String)> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR")
+        .map_err(|_| syn::Error::new(path.span(), "CARGO_MANIFEST_DIR is not set"))?;
+    let absolute = PathBuf::from(manifest_dir).join(path.value());
+    let markdown = std::fs::read_to_string(&absolute).map_err(|err| {
+        syn::Error::new(
+            path.span(),
+            format!("Failed to read {}: {err}", absolute.display()),
+        )
+    })?;
+    Ok((absolute.to_string_lossy().to_string(), markdown))
+}
+
+/// Returns the front matter inputs and the prompt body,
+/// the front matter is checked against the same schema as at runtime
+pub fn split_front_matter(markdown: &str) -> Result<(BTreeMap<String, InputSpec>, &str), String> {
+    let (front_matter, body) =
+        promptpunch_markdown::split_front_matter(markdown).map_err(|err| format!("{err:#}"))?;
+    Ok((front_matter.inputs, body))
+}
+
+/// Whether the input has a front matter default
+pub fn has_default(inputs: &BTreeMap<String, InputSpec>, name: &str) -> bool {
+    inputs.get(name).is_some_and(|spec| spec.default.is_some())
+}
+
+pub fn check_body(body: &str) -> Result<(), String> {
+    let mut has_sections = false;
+    for (idx, line) in body.lines().enumerate() {
+        if parse_role_header(line).is_some() {
+            has_sections = true;
+        } else if parse_include(line).is_some() {
+            return Err(format!(
+                "Includes are not supported in embedded prompts, found on line {}",
+                idx + 1
+            ));
+        } else if !has_sections && !line.trim().is_empty() {
+            return Err(format!(
+                "Prompt content on line {} precedes any role header",
+                idx + 1
+            ));
+        }
+    }
+    if !has_sections {
+        return Err("Prompt has no role sections".to_string());
+    }
+    Ok(())
+}
+
+/// Placeholder names including braces in the order of occurrence
+pub fn find_placeholders(body: &str) -> Vec<String> {
+    promptpunch_markdown::find_placeholders(body.lines())
+        .into_iter()
+        .map(|placeholder| placeholder.name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_default, split_front_matter};
+
+    #[test]
+    fn checks_front_matter_schema() {
+        let markdown = "---\ninputs:\n  words:\n    default: 50\n---\n# User\nHi\n";
+        let (inputs, body) = split_front_matter(markdown).unwrap();
+        assert!(has_default(&inputs, "words"));
+        assert_eq!(body, "# User\nHi\n");
+
+        let err = split_front_matter("---\ntemprature: 0.5\n---\n# User\n").unwrap_err();
+        assert!(err.contains("unknown field `temprature`"));
+        assert!(split_front_matter("---\noutput: xml\n---\n# User\n").is_err());
+        let markdown = "---\ninputs:\n  words:\n    type: float\n---\n# User\n";
+        assert!(split_front_matter(markdown).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Markdown prompt syntax shared by `promptpunch` and its compile-time macros,
+//! use it through `promptpunch::prompt`
+
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Placeholder occurrence found in a markdown prompt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placeholder {
+    /// Placeholder as written in the prompt, including braces, e.g. `{topic}`
+    pub name: String,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column of the opening brace
+    pub column: usize,
+}
+
+/// Scans prompt lines for `{placeholder}` occurrences.
+/// Only braces wrapping an identifier (letters, digits, `_`, `-`, `.`) are considered,
+/// so JSON examples inside the prompt are not reported.
+/// Escaped `{{placeholder}}` is literal text, see [`replace_placeholders`].
+pub fn find_placeholders(lines: impl IntoIterator<Item = impl AsRef<str>>) -> Vec<Placeholder> {
+    let mut placeholders = vec![];
+    for (idx, line) in lines.into_iter().enumerate() {
+        let line = line.as_ref();
+        let mut offset = 0;
+        while let Some(start) = line[offset..].find('{') {
+            let at = offset + start;
+            let text = &line[at..];
+            if let Some(len) = escaped_placeholder_len(text) {
+                offset = at + len;
+            } else if let Some(len) = placeholder_len(text) {
+                placeholders.push(Placeholder {
+                    name: text[..len].to_string(),
+                    line: idx + 1,
+                    column: line[..at].chars().count() + 1,
+                });
+                offset = at + len;
+            } else {
+                offset = at + 1;
+            }
+        }
+    }
+    placeholders
+}
+
+/// Replaces every `{placeholder}` the closure returns a value for,
+/// escaped `{{placeholder}}` becomes literal `{placeholder}`
+pub fn replace_placeholders(text: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        replaced.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let len = if let Some(len) = escaped_placeholder_len(rest) {
+            replaced.push_str(&rest[1..len - 1]);
+            len
+        } else if let Some(len) = placeholder_len(rest) {
+            match value(&rest[..len]) {
+                Some(value) => replaced.push_str(&value),
+                None => replaced.push_str(&rest[..len]),
+            }
+            len
+        } else {
+            replaced.push('{');
+            1
+        };
+        rest = &rest[len..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// Escapes every `{placeholder}` of literal text as `{{placeholder}}`
+pub fn escape_placeholders(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        escaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let len = match placeholder_len(rest) {
+            Some(len) => {
+                escaped.push_str(&format!("{{{}}}", &rest[..len]));
+                len
+            }
+            None => {
+                escaped.push('{');
+                1
+            }
+        };
+        rest = &rest[len..];
+    }
+    escaped.push_str(rest);
+    escaped
+}
+
+/// Length of the `{identifier}` the text starts with
+fn placeholder_len(text: &str) -> Option<usize> {
+    let after = text.strip_prefix('{')?;
+    let ident_len = after
+        .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+        .unwrap_or(after.len());
+    (ident_len > 0 && after[ident_len..].starts_with('}')).then_some(ident_len + 2)
+}
+
+/// Length of the `{{identifier}}` the text starts with
+fn escaped_placeholder_len(text: &str) -> Option<usize> {
+    let len = placeholder_len(text.strip_prefix('{')?)?;
+    text[1 + len..].starts_with('}').then_some(len + 2)
+}
+
+/// Optional YAML block at the top of a markdown prompt, delimited by `---` lines
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FrontMatter {
+    /// Name in the prompt registry, defaults to the file path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Expected input variables keyed by name, `document` is injected into `{document}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub inputs: BTreeMap<String, InputSpec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputFormat>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InputSpec {
+    #[serde(rename = "type", default)]
+    pub kind: InputType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Makes the input optional
+    #[serde(
+        default,
+        deserialize_with = "deserialize_scalar",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default: Option<String>,
+}
+
+fn deserialize_scalar<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde_yaml::Value;
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(Value::Number(value)) => Ok(Some(value.to_string())),
+        Some(Value::Bool(value)) => Ok(Some(value.to_string())),
+        Some(_) => Err(serde::de::Error::custom("default must be a scalar value")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Json,
+}
+
+impl InputType {
+    /// Checks that the value can be parsed as the type
+    pub fn check(&self, value: &str) -> anyhow::Result<()> {
+        let value = value.trim();
+        match self {
+            InputType::String => {}
+            InputType::Integer => {
+                value.parse::<i64>()?;
+            }
+            InputType::Number => {
+                value.parse::<f64>()?;
+            }
+            InputType::Boolean => {
+                value.parse::<bool>()?;
+            }
+            InputType::Json => {
+                serde_json::from_str::<serde_json::Value>(value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits the markdown into front matter and prompt body.
+/// Markdown without front matter is returned as is with the default front matter.
+pub fn split_front_matter(markdown: &str) -> anyhow::Result<(FrontMatter, &str)> {
+    let Some(rest) = markdown
+        .strip_prefix("---\n")
+        .or_else(|| markdown.strip_prefix("---\r\n"))
+    else {
+        return Ok((FrontMatter::default(), markdown));
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            let front_matter = serde_yaml::from_str::<Option<FrontMatter>>(&rest[..offset])
+                .context("Failed to parse prompt front matter")?
+                .unwrap_or_default();
+            return Ok((front_matter, &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    anyhow::bail!("Prompt front matter is not closed with ---")
+}
+
+/// Path of a `{% include "path" %}` line
+pub fn parse_include(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("{%")?
+        .strip_suffix("%}")?
+        .trim()
+        .strip_prefix("include")?
+        .trim()
+        .strip_prefix('"')?
+        .strip_suffix('"')
+}
+
+/// Role of a `# System`, `# User` or `# Assistant` header line
+pub fn parse_role_header(line: &str) -> Option<Role> {
+    if !line.starts_with('#') {
+        return None;
+    }
+    match line.replace("#", "").trim().to_lowercase().as_str() {
+        "system" => Some(Role::System),
+        "user" => Some(Role::User),
+        "assistant" => Some(Role::Assistant),
+        _ => None,
+    }
+}
+
+fn is_escaped_role_header(line: &str) -> bool {
+    let unescaped = line.trim_start_matches('\\');
+    unescaped.len() < line.len() && parse_role_header(unescaped).is_some()
+}
+
+/// Content of a line escaped by [`escape_role_header`]
+pub fn unescape_role_header(line: &str) -> &str {
+    match is_escaped_role_header(line) {
+        true => &line[1..],
+        false => line,
+    }
+}
+
+/// Content line safe to write into a markdown prompt
+pub fn escape_role_header(line: &str) -> String {
+    match parse_role_header(line).is_some() || is_escaped_role_header(line) {
+        true => format!("\\{line}"),
+        false => line.to_string(),
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+use crate::{
+    llm::{cache::fnv1a, LlmProvider},
+    prompt::{find_placeholders, formats::PromptTemplate, InjectableData},
+    Prompt,
+};
+
+/// Placeholder values of a single completion keyed by the placeholder name without braces
+pub type BatchRow = BTreeMap<String, String>;
+
+/// Outcome of a row written as a line of the output JSONL
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchResult {
+    /// Position of the row in the dataset starting from 0
+    pub row: usize,
+    pub input: BatchRow,
+    pub response: Option<String>,
+    pub user_tokens: usize,
+    pub assistant_tokens: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub completed: usize,
+    pub failed: usize,
+    /// Rows completed by the previous runs
+    pub skipped: usize,
+}
+
+/// Reads rows from `.jsonl`/`.ndjson` with an object per line or `.csv` with a header
+pub fn read_rows(path: impl AsRef<Path>) -> anyhow::Result<Vec<BatchRow>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read dataset from {}", path.display()))?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("jsonl") | Some("ndjson") => parse_jsonl_rows(&text),
+        Some("csv") => parse_csv_rows(&text),
+        _ => anyhow::bail!(
+            "Unknown dataset format of {}, expected .jsonl or .csv",
+            path.display()
+        ),
+    }
+    .with_context(|| format!("Invalid dataset {}", path.display()))
+}
+
+/// Strings are taken as is, other JSON values are injected as JSON
+pub fn parse_jsonl_rows(jsonl: &str) -> anyhow::Result<Vec<BatchRow>> {
+    let mut rows = vec![];
+    for (idx, line) in jsonl.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object = serde_json::from_str::<BTreeMap<String, Value>>(line)
+            .with_context(|| format!("Line {} is not a JSON object", idx + 1))?;
+        let row = object
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            })
+            .collect();
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// CSV with a header row, quoted fields may contain commas, newlines and `""`
+pub fn parse_csv_rows(csv: &str) -> anyhow::Result<Vec<BatchRow>> {
+    let mut records = parse_csv(csv.trim_start_matches('\u{feff}'))?.into_iter();
+    let Some(header) = records.next() else {
+        return Ok(vec![]);
+    };
+    let mut rows = vec![];
+    for (idx, record) in records.enumerate() {
+        if record.len() != header.len() {
+            anyhow::bail!(
+                "Record {} has {} fields while the header has {}",
+                idx + 1,
+                record.len(),
+                header.len()
+            );
+        }
+        rows.push(header.iter().cloned().zip(record).collect());
+    }
+    Ok(rows)
+}
+
+fn parse_csv(csv: &str) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                if record != [""] {
+                    records.push(std::mem::take(&mut record));
+                }
+                record.clear();
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        anyhow::bail!("Quoted field is not closed");
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Changes the rendered prompt of every row, e.g. its model or timeout
+pub type PromptOverride = Arc<dyn Fn(&mut Prompt) -> anyhow::Result<()> + Send + Sync>;
+
+/// Completes a prompt template for every row of a dataset
+pub struct Batch<P> {
+    provider: Arc<P>,
+    template: PromptTemplate,
+    prompt_override: Option<PromptOverride>,
+    concurrency: usize,
+}
+
+impl<P: LlmProvider + 'static> Batch<P> {
+    pub fn new(provider: P, template: PromptTemplate) -> Self {
+        Self {
+            provider: Arc::new(provider),
+            template,
+            prompt_override: None,
+            concurrency: 4,
+        }
+    }
+
+    /// Number of completions requested at the same time
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Applied to the prompt of every row before the completion,
+    /// e.g. `|prompt| Ok(prompt.temperature = 0.0)`
+    pub fn with_prompt_override(
+        mut self,
+        prompt_override: impl Fn(&mut Prompt) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.prompt_override = Some(Arc::new(prompt_override));
+        self
+    }
+
+    /// Appends a [`BatchResult`] line per row to the output as soon as it completes.
+    ///
+    /// Rows completed by a previous run into the same output are skipped wherever they moved
+    /// in the dataset, the failed ones are removed from the output and retried.
+    /// The kept results are written to a temporary file replacing the output.
+    pub async fn run(
+        &self,
+        rows: &[BatchRow],
+        output: impl AsRef<Path>,
+    ) -> anyhow::Result<BatchSummary> {
+        let output = output.as_ref();
+        let finished = read_finished(output)?;
+        let mut temp_path = output.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut temp = std::fs::File::create(&temp_path)
+            .with_context(|| format!("Failed to write results to {}", output.display()))?;
+        for result in finished.values() {
+            writeln!(temp, "{}", serde_json::to_string(result)?)?;
+        }
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, output)
+            .with_context(|| format!("Failed to write results to {}", output.display()))?;
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(output)
+            .with_context(|| format!("Failed to write results to {}", output.display()))?;
+
+        let mut summary = BatchSummary::default();
+        let placeholders = find_placeholders(self.template.text.lines())
+            .into_iter()
+            .map(|placeholder| placeholder.name)
+            .collect::<BTreeSet<_>>();
+        let pending = rows
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| !finished.contains_key(&row_hash(row)))
+            .collect::<Vec<_>>();
+        summary.skipped = rows.len() - pending.len();
+        let mut pending = pending.into_iter();
+        let mut tasks = JoinSet::new();
+        loop {
+            while tasks.len() < self.concurrency {
+                let Some((idx, row)) = pending.next() else {
+                    break;
+                };
+                // Extra columns such as ids are not reported as unused placeholders
+                let data = row
+                    .iter()
+                    .map(|(name, value)| (format!("{{{name}}}"), value))
+                    .filter(|(placeholder, _)| placeholders.contains(placeholder))
+                    .map(|(placeholder, value)| InjectableData::new(placeholder, value))
+                    .collect::<Vec<_>>();
+                let provider = self.provider.clone();
+                let template = self.template.clone();
+                let prompt_override = self.prompt_override.clone();
+                let row = row.clone();
+                tasks.spawn(async move {
+                    let result =
+                        complete_row(provider.as_ref(), &template, prompt_override, &data).await;
+                    into_result(idx, row, result)
+                });
+            }
+            let Some(result) = tasks.join_next().await else {
+                break;
+            };
+            let result = result.context("Batch completion panicked")?;
+            match result.error {
+                Some(_) => summary.failed += 1,
+                None => summary.completed += 1,
+            }
+            writeln!(file, "{}", serde_json::to_string(&result)?)?;
+            file.flush()?;
+        }
+        Ok(summary)
+    }
+}
+
+async fn complete_row<P: LlmProvider>(
+    provider: &P,
+    template: &PromptTemplate,
+    prompt_override: Option<PromptOverride>,
+    data: &[InjectableData],
+) -> anyhow::Result<crate::Completion> {
+    let mut prompt = template.render(data)?;
+    if let Some(prompt_override) = prompt_override {
+        prompt_override(&mut prompt)?;
+    }
+    provider.complete_chat(&prompt).await
+}
+
+fn into_result(
+    row: usize,
+    input: BatchRow,
+    result: anyhow::Result<crate::Completion>,
+) -> BatchResult {
+    let mut batch_result = BatchResult {
+        row,
+        input,
+        response: None,
+        user_tokens: 0,
+        assistant_tokens: 0,
+        error: None,
+    };
+    match result.and_then(|completion| {
+        let response = completion.last_assistant_response()?;
+        Ok((completion, response))
+    }) {
+        Ok((completion, response)) => {
+            batch_result.response = Some(response);
+            batch_result.user_tokens = completion.user_tokens;
+            batch_result.assistant_tokens = completion.assistant_tokens;
+        }
+        Err(err) => batch_result.error = Some(format!("{err:#}")),
+    }
+    batch_result
+}
+
+/// Identifies a row by its values, stable across builds and row order
+fn row_hash(row: &BatchRow) -> u64 {
+    let mut bytes = vec![];
+    for (name, value) in row {
+        for part in [name, value] {
+            bytes.extend_from_slice(&part.len().to_le_bytes());
+            bytes.extend_from_slice(part.as_bytes());
+        }
+    }
+    fnv1a(&bytes)
+}
+
+/// Successful results of the previous runs keyed by [`row_hash`],
+/// a line cut by an interruption is ignored
+fn read_finished(output: &Path) -> anyhow::Result<BTreeMap<u64, BatchResult>> {
+    if !output.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let text = std::fs::read_to_string(output)
+        .with_context(|| format!("Failed to read results from {}", output.display()))?;
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str::<BatchResult>(line).ok())
+        .filter(|result| result.error.is_none())
+        .map(|result| (row_hash(&result.input), result))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{parse_csv_rows, parse_jsonl_rows, Batch, BatchResult, BatchSummary};
+    use crate::{
+        prompt::formats::{PromptFormat, PromptTemplate},
+        test_util::{Echo, TempDir},
+    };
+
+    #[test]
+    fn parses_rows() {
+        let rows =
+            parse_csv_rows("id,text\r\n1,\"Hello, \"\"world\"\"\nagain\"\n2,bye\n\n").unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["text"], "Hello, \"world\"\nagain");
+        assert_eq!(rows[1]["id"], "2");
+        assert!(parse_csv_rows("id,text\n1\n").is_err());
+
+        let rows =
+            parse_jsonl_rows("{\"text\": \"hi\", \"tags\": [1]}\n\n{\"text\": \"bye\"}\n").unwrap();
+        assert_eq!(rows[0]["text"], "hi");
+        assert_eq!(rows[0]["tags"], "[1]");
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn resumes_batch() {
+        let dir = TempDir::new("batch");
+        let output = dir.join("output.jsonl");
+        let rows = parse_csv_rows("id,text\n1,one\n2,fail\n3,three\n").unwrap();
+        let previous = BatchResult {
+            row: 0,
+            input: rows[0].clone(),
+            response: Some("ONE".to_string()),
+            user_tokens: 1,
+            assistant_tokens: 1,
+            error: None,
+        };
+        std::fs::write(
+            &output,
+            format!(
+                "{}\n{{\"row\": 2, \"inp",
+                serde_json::to_string(&previous).unwrap()
+            ),
+        )
+        .unwrap();
+
+        let batch = Batch::new(
+            Echo::default(),
+            PromptTemplate::new(PromptFormat::Markdown, "# User\nSay {text}"),
+        )
+        .with_concurrency(2)
+        .with_prompt_override(|prompt| {
+            prompt.model = Some("echo".to_string());
+            Ok(())
+        });
+        let summary = batch.run(&rows, &output).await.unwrap();
+        assert_eq!(
+            summary,
+            BatchSummary {
+                completed: 1,
+                failed: 1,
+                skipped: 1
+            }
+        );
+        assert_eq!(batch.provider.calls.load(Ordering::SeqCst), 2);
+        let model = batch.provider.model.lock().unwrap().clone();
+        assert_eq!(model.as_deref(), Some("echo"));
+
+        let results = std::fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<BatchResult>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], previous);
+        let last = results.iter().find(|result| result.row == 2).unwrap();
+        assert_eq!(last.response.as_deref(), Some("echo Say three"));
+
+        // Rows are matched by their values after the dataset changed
+        let rows = parse_csv_rows("id,text\n0,zero\n3,three\n2,fail\n1,one\n").unwrap();
+        let summary = batch.run(&rows, &output).await.unwrap();
+        assert_eq!(summary.skipped, 2);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.completed, 1);
+        assert_eq!(batch.provider.calls.load(Ordering::SeqCst), 4);
+        let lines = std::fs::read_to_string(&output).unwrap().lines().count();
+        assert_eq!(lines, 4);
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    llm::{
+        chat_gpt::ChatGpt,
+        rate_limit::{RateLimit, RateLimiter},
+        LlmProvider,
+    },
+    Prompt,
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum ProviderKind {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+}
+
+impl FromStr for ProviderKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "openai" => Ok(ProviderKind::OpenAi),
+            unknown => anyhow::bail!("Unknown provider {unknown}, expected openai"),
+        }
+    }
+}
+
+/// Provider and completion settings, unset fields keep the prompt and provider defaults.
+/// The model of a config profile is the default of the provider, see [`Profile::chat_gpt`],
+/// prompts setting their own model keep it. Its temperature applies to prompts
+/// without a file only, e.g. a chat started from scratch.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub provider: Option<ProviderKind>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub seed: Option<u64>,
+    /// Client-side limits of every model, see [`RateLimiter`]
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+    /// Seconds the whole completion of a prompt may take
+    pub timeout: Option<u64>,
+}
+
+impl Profile {
+    /// Settings of `overrides` win over the own ones
+    pub fn merge(self, overrides: Profile) -> Profile {
+        Profile {
+            provider: overrides.provider.or(self.provider),
+            base_url: overrides.base_url.or(self.base_url),
+            model: overrides.model.or(self.model),
+            temperature: overrides.temperature.or(self.temperature),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            seed: overrides.seed.or(self.seed),
+            requests_per_minute: overrides.requests_per_minute.or(self.requests_per_minute),
+            tokens_per_minute: overrides.tokens_per_minute.or(self.tokens_per_minute),
+            timeout: overrides.timeout.or(self.timeout),
+        }
+    }
+
+    /// Overrides the model, temperature and timeout of the prompt
+    pub fn apply_to_prompt(&self, prompt: &mut Prompt) -> anyhow::Result<()> {
+        if let Some(model) = &self.model {
+            prompt.model = Some(model.clone());
+        }
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                anyhow::bail!("Temperature must be between 0 and 2, got {temperature}");
+            }
+            prompt.temperature = temperature;
+        }
+        if let Some(timeout) = self.timeout {
+            prompt.timeout = Some(Duration::from_secs(timeout));
+        }
+        Ok(())
+    }
+
+    /// Provider of the selected kind
+    pub fn provider(&self) -> anyhow::Result<Box<dyn LlmProvider>> {
+        match self.provider.unwrap_or_default() {
+            ProviderKind::OpenAi => Ok(Box::new(self.chat_gpt()?)),
+        }
+    }
+
+    /// Client of the OpenAI compatible API with the key from `OPENAI_API_KEY`
+    pub fn chat_gpt(&self) -> anyhow::Result<ChatGpt> {
+        let mut llm = ChatGpt::from_env();
+        if let Some(base_url) = &self.base_url {
+            llm = llm.with_base_url(base_url);
+        }
+        if let Some(model) = &self.model {
+            llm = llm.with_model(model.parse()?);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            llm = llm.with_max_tokens(max_tokens);
+        }
+        if let Some(seed) = self.seed {
+            llm = llm.with_seed(seed);
+        }
+        if self.requests_per_minute.is_some() || self.tokens_per_minute.is_some() {
+            llm = llm.with_rate_limiter(RateLimiter::new(RateLimit {
+                requests_per_minute: self.requests_per_minute,
+                tokens_per_minute: self.tokens_per_minute,
+            }));
+        }
+        Ok(llm)
+    }
+}
+
+/// Named profiles, e.g.
+///
+/// ```toml
+/// default_profile = "fast"
+///
+/// [profiles.fast]
+/// model = "gpt-4o-mini"
+/// temperature = 0.2
+/// requests_per_minute = 500
+///
+/// [profiles.local]
+/// base_url = "http://localhost:11434/v1"
+/// model = "llama3.1"
+/// ```
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Profile used when none is selected
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/promptpunch/config.toml` or `~/.config/promptpunch/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_dir.join("promptpunch").join("config.toml"))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config from {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Config from the default path, empty when there is no file
+    pub fn load_default() -> anyhow::Result<Self> {
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Profile by name, without name the default profile if any
+    pub fn profile(&self, name: Option<&str>) -> anyhow::Result<Profile> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(Profile::default());
+        };
+        self.profiles
+            .get(name)
+            .cloned()
+            .with_context(|| format!("Profile {name} is not found in the config"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Profile};
+    use crate::prelude::*;
+
+    #[test]
+    fn selects_profiles() {
+        let config: Config = toml::from_str(
+            r#"
+            default_profile = "fast"
+
+            [profiles.fast]
+            model = "gpt-4o-mini"
+            temperature = 0.2
+
+            [profiles.local]
+            base_url = "http://localhost:11434/v1"
+            model = "llama3.1"
+            seed = 7
+            "#,
+        )
+        .unwrap();
+
+        let fast = config.profile(None).unwrap();
+        assert_eq!(fast.model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(config.profile(Some("local")).unwrap().seed, Some(7));
+        assert!(config.profile(Some("missing")).is_err());
+
+        let settings = fast.merge(Profile {
+            temperature: Some(1.0),
+            ..Default::default()
+        });
+        let mut prompt = PromptBuilder::default()
+            .user("Hello")
+            .model("gpt-4o".to_string())
+            .build()
+            .unwrap();
+        settings.apply_to_prompt(&mut prompt).unwrap();
+        assert_eq!(prompt.model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(prompt.temperature, 1.0);
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt::Display;
+
+use crate::{
+    llm::{LlmProvider, TokenSink},
+    message, Completion, Prompt, PromptMessage, PromptMessageRequest, Role,
+};
+
+/// Multi-turn chat session on top of an [`LlmProvider`]
+/// keeping the history, parameters and token usage across turns
+pub struct Conversation<P> {
+    provider: P,
+    /// History and parameters of the next request
+    prompt: Prompt,
+    /// Usage of the whole history as reported by the provider for the last request,
+    /// every completion already counts the earlier turns
+    pub user_tokens: usize,
+    pub assistant_tokens: usize,
+}
+
+impl<P: LlmProvider> Conversation<P> {
+    /// Starts conversation seeded with the prompt messages and parameters,
+    /// its completion points are resolved with the first [`Conversation::send`]
+    pub fn new(provider: P, prompt: Prompt) -> Self {
+        Self {
+            provider,
+            prompt,
+            user_tokens: 0,
+            assistant_tokens: 0,
+        }
+    }
+
+    /// Appends user message and returns the assistant reply
+    pub async fn send(&mut self, user_message: impl Display) -> anyhow::Result<String> {
+        let mut prompt = self.prompt.clone();
+        prompt.messages.push(message::user!(user_message));
+        let completion = self.provider.complete_chat(&prompt).await?;
+        self.record(completion)
+    }
+
+    /// Completes the history as is, e.g. a seed prompt ending with a user message
+    pub async fn complete(&mut self) -> anyhow::Result<String> {
+        let completion = self.provider.complete_chat(&self.prompt).await?;
+        self.record(completion)
+    }
+
+    /// Same as [`Conversation::send`] streaming the reply into the sink
+    pub async fn send_streaming(
+        &mut self,
+        user_message: impl Display,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<String> {
+        let mut prompt = self.prompt.clone();
+        prompt.messages.push(message::user!(user_message));
+        let completion = self.provider.complete_chat_streaming(&prompt, sink).await?;
+        self.record(completion)
+    }
+
+    /// Same as [`Conversation::complete`] streaming the reply into the sink
+    pub async fn complete_streaming(&mut self, sink: TokenSink<'_>) -> anyhow::Result<String> {
+        let completion = self
+            .provider
+            .complete_chat_streaming(&self.prompt, sink)
+            .await?;
+        self.record(completion)
+    }
+
+    /// Removes the last user message with the replies to it, returns the removed message
+    pub fn undo(&mut self) -> Option<String> {
+        let idx = self.prompt.messages.iter().rposition(|request| {
+            matches!(request, PromptMessageRequest::Message { body } if body.role == Role::User)
+        })?;
+        let removed = self.prompt.messages.split_off(idx);
+        match removed.into_iter().next() {
+            Some(PromptMessageRequest::Message { body }) => Some(body.content),
+            _ => None,
+        }
+    }
+
+    /// Regenerates the reply to the last user message
+    pub async fn retry(&mut self) -> anyhow::Result<String> {
+        let user_message = self
+            .undo()
+            .ok_or_else(|| anyhow::anyhow!("There is no user message to retry"))?;
+        self.send(user_message).await
+    }
+
+    /// Same as [`Conversation::retry`] streaming the reply into the sink
+    pub async fn retry_streaming(&mut self, sink: TokenSink<'_>) -> anyhow::Result<String> {
+        let user_message = self
+            .undo()
+            .ok_or_else(|| anyhow::anyhow!("There is no user message to retry"))?;
+        self.send_streaming(user_message, sink).await
+    }
+
+    pub fn messages(&self) -> Vec<PromptMessage> {
+        self.prompt
+            .messages
+            .iter()
+            .filter_map(|request| match request {
+                PromptMessageRequest::Message { body } => Some(body.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Parameters of the following requests, e.g. temperature or model
+    pub fn prompt_mut(&mut self) -> &mut Prompt {
+        &mut self.prompt
+    }
+
+    pub fn prompt(&self) -> &Prompt {
+        &self.prompt
+    }
+
+    /// History as a completion, e.g. to save the transcript
+    pub fn to_completion(&self) -> Completion {
+        Completion {
+            messages: self.messages(),
+            user_tokens: self.user_tokens,
+            assistant_tokens: self.assistant_tokens,
+            served_by: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, completion: Completion) -> anyhow::Result<String> {
+        let reply = completion.last_assistant_response()?;
+        self.user_tokens = completion.user_tokens;
+        self.assistant_tokens = completion.assistant_tokens;
+        self.prompt.messages = completion
+            .messages
+            .into_iter()
+            .map(|body| PromptMessageRequest::Message { body })
+            .collect();
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Conversation;
+    use crate::{prelude::*, test_util::Counter};
+
+    #[tokio::test]
+    async fn keeps_history() {
+        let prompt = PromptBuilder::default()
+            .system("Act like a counter")
+            .user("Count")
+            .complete()
+            .build()
+            .unwrap();
+        let mut conversation = Conversation::new(Counter::default(), prompt);
+        assert_eq!(conversation.send("Again").await.unwrap(), "reply 2");
+        assert_eq!(conversation.send("And again").await.unwrap(), "reply 3");
+        assert_eq!(conversation.messages().len(), 7);
+        assert_eq!(conversation.user_tokens, 4);
+        assert_eq!(conversation.assistant_tokens, 3);
+
+        assert_eq!(conversation.undo().as_deref(), Some("And again"));
+        assert_eq!(conversation.messages().len(), 5);
+        assert_eq!(conversation.retry().await.unwrap(), "reply 2");
+
+        let mut streamed = String::new();
+        let reply = conversation
+            .send_streaming("Stream", &mut |token: &str| streamed.push_str(token))
+            .await
+            .unwrap();
+        assert_eq!(reply, "reply 3");
+        assert_eq!(streamed, "reply 3");
+        conversation.undo();
+
+        let completion = conversation.to_completion();
+        let prompt = completion.continue_with("Once more");
+        assert_eq!(prompt.messages.len(), 6);
+        assert_eq!(prompt.messages[5], message::user!("Once more"));
+    }
+}
@@ -1,99 +1,489 @@
 use anyhow::Context;
 use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::time::Duration;
 
+pub mod batch;
+pub mod config;
+pub mod conversation;
 pub mod llm;
 pub mod prompt;
 
+pub use promptpunch_markdown::{OutputFormat, Role};
+
 #[cfg(feature = "web")]
 pub mod web;
 
-#[derive(Debug, Builder, Clone)]
-#[builder(setter(into))]
+#[cfg(test)]
+mod test_util;
+
+#[cfg(feature = "macros")]
+pub use promptpunch_macros::prompt;
+
+#[cfg(any(test, feature = "macros"))]
+extern crate self as promptpunch;
+
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow;
+    pub use serde_json;
+}
+
+#[derive(Debug, Builder, Clone, Serialize, Deserialize)]
+#[builder(setter(into), build_fn(validate = "Self::validate"))]
 pub struct Prompt {
+    /// Empty by default, e.g. for a chat started without a prompt
+    #[builder(default)]
     pub messages: Vec<PromptMessageRequest>,
-    #[builder(default = "0.3")]
+    #[builder(default = "default_temperature()")]
+    #[serde(default = "default_temperature")]
     pub temperature: f32,
+    /// Overrides the model configured on the provider
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[builder(default)]
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Time limit of the whole completion including every turn
+    #[builder(default)]
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) fn default_temperature() -> f32 {
+    0.3
+}
+
+impl Prompt {
+    pub fn to_markdown(&self) -> String {
+        prompt::write_markdown_prompt(self)
+    }
+}
+
+impl PromptBuilder {
+    pub fn system(&mut self, content: impl Display) -> &mut Self {
+        self.push(message::system!(content))
+    }
+
+    pub fn user(&mut self, content: impl Display) -> &mut Self {
+        self.push(message::user!(content))
+    }
+
+    pub fn assistant(&mut self, content: impl Display) -> &mut Self {
+        self.push(message::assistant!(content))
+    }
+
+    /// Waits for the assistant response before the following messages
+    pub fn complete(&mut self) -> &mut Self {
+        self.push(message::complete!())
+    }
+
+    /// Waits for the assistant response generated with the overridden parameters
+    pub fn complete_with(&mut self, params: CompletionParams) -> &mut Self {
+        self.push(PromptMessageRequest::WaitCompletionWith { params })
+    }
+
+    /// Appends messages of the markdown prompt, front matter parameters are ignored
+    pub fn extend_from_markdown(
+        &mut self,
+        markdown: &str,
+        injectable_data: &[prompt::InjectableData],
+    ) -> anyhow::Result<&mut Self> {
+        let parsed = prompt::parse_markdown_prompt(markdown, injectable_data)?;
+        self.messages
+            .get_or_insert_with(Vec::new)
+            .extend(parsed.messages);
+        Ok(self)
+    }
+
+    fn push(&mut self, request: PromptMessageRequest) -> &mut Self {
+        self.messages.get_or_insert_with(Vec::new).push(request);
+        self
+    }
+
+    /// Messages built in code must not request a completion before any user message
+    /// or twice in a row, prompts parsed from files are not checked
+    fn validate(&self) -> Result<(), String> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(format!("Temperature {temperature} is out of 0..=2 range"));
+            }
+        }
+        let mut has_user_message = false;
+        let mut previous_is_completion = false;
+        for (idx, request) in self.messages.iter().flatten().enumerate() {
+            match request {
+                PromptMessageRequest::Message { body } => {
+                    has_user_message |= body.role == Role::User;
+                    previous_is_completion = false;
+                }
+                PromptMessageRequest::WaitCompletion
+                | PromptMessageRequest::WaitCompletionWith { .. } => {
+                    if !has_user_message {
+                        return Err(format!(
+                            "Completion point #{} precedes any user message",
+                            idx + 1
+                        ));
+                    }
+                    if previous_is_completion {
+                        return Err(format!(
+                            "Completion point #{} follows another completion point",
+                            idx + 1
+                        ));
+                    }
+                    previous_is_completion = true;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parameters overridden for a single completion, see [`PromptBuilder::complete_with`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CompletionParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_format: Option<OutputFormat>,
+}
+
+/// Not `Eq` since 0.3 because of the `f32` temperature of [`CompletionParams`],
+/// non-exhaustive to add new requests without breaking matches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
 pub enum PromptMessageRequest {
     Message { body: PromptMessage },
     WaitCompletion,
+    WaitCompletionWith { params: CompletionParams },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PromptMessage {
     pub role: Role,
     pub content: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Role {
-    System,
-    User,
-    Assistant,
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Completion {
     pub messages: Vec<PromptMessage>,
     pub user_tokens: usize,
     pub assistant_tokens: usize,
+    /// Backend of every turn completed by the request in order,
+    /// empty unless the provider is or wraps a [`llm::router::Router`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub served_by: Vec<String>,
 }
 impl Completion {
     pub fn last_assistant_response(&self) -> anyhow::Result<String> {
         let last_message = self
             .messages
             .iter()
-            .filter(|msg| msg.role == Role::Assistant)
-            .last()
+            .rfind(|msg| msg.role == Role::Assistant)
             .context("There is no assistant message")?;
         Ok(last_message.content.clone())
     }
+
+    /// Conversation as a markdown prompt, reusable as a new prompt
+    pub fn to_markdown(&self) -> String {
+        prompt::write_markdown_transcript(self)
+    }
+
+    /// Prompt with the whole conversation followed by the new user message,
+    /// parameters are the defaults, use [`conversation::Conversation`] to keep them across turns
+    pub fn continue_with(&self, user_message: impl Display) -> Prompt {
+        let mut messages = self
+            .messages
+            .iter()
+            .cloned()
+            .map(|body| PromptMessageRequest::Message { body })
+            .collect::<Vec<_>>();
+        messages.push(message::user!(user_message));
+        Prompt {
+            messages,
+            temperature: default_temperature(),
+            model: None,
+            output_format: OutputFormat::default(),
+            timeout: None,
+        }
+    }
 }
 
 pub mod prelude {
     pub use crate::{
-        llm::chat_gpt::ChatGpt, llm::LlmProvider, message, prompt::InjectableData, Prompt,
+        conversation::Conversation, llm::chat_gpt::ChatGpt, llm::LlmProvider, message,
+        prompt::InjectableData, prompt::PromptInput, CompletionParams, OutputFormat, Prompt,
         PromptBuilder, PromptMessage, PromptMessageRequest, Role,
     };
 }
 
+/// Message macros, a single argument is taken as is (`{placeholder}` stays intact),
+/// several arguments are formatted like `format!`
 pub mod message {
     pub use crate::PromptMessageRequest;
 
+    #[doc(hidden)]
     #[macro_export]
-    macro_rules! system {
-        ($content:expr) => {
-            PromptMessageRequest::Message {
-                body: PromptMessage {
-                    role: Role::System,
+    macro_rules! __message {
+        ($role:ident, $content:expr) => {
+            $crate::PromptMessageRequest::Message {
+                body: $crate::PromptMessage {
+                    role: $crate::Role::$role,
                     content: $content.to_string(),
                 },
             }
         };
+        ($role:ident, $format:expr, $($arg:tt)*) => {
+            $crate::PromptMessageRequest::Message {
+                body: $crate::PromptMessage {
+                    role: $crate::Role::$role,
+                    content: ::std::format!($format, $($arg)*),
+                },
+            }
+        };
+    }
+
+    #[macro_export]
+    macro_rules! system {
+        ($($content:tt)+) => {
+            $crate::__message!(System, $($content)+)
+        };
     }
 
     #[macro_export]
     macro_rules! user {
-        ($content:expr) => {
-            PromptMessageRequest::Message {
-                body: PromptMessage {
-                    role: Role::User,
-                    content: $content.to_string(),
-                },
-            }
+        ($($content:tt)+) => {
+            $crate::__message!(User, $($content)+)
+        };
+    }
+
+    #[macro_export]
+    macro_rules! assistant {
+        ($($content:tt)+) => {
+            $crate::__message!(Assistant, $($content)+)
         };
     }
 
     #[macro_export]
     macro_rules! complete {
         () => {
-            PromptMessageRequest::WaitCompletion
+            $crate::PromptMessageRequest::WaitCompletion
+        };
+    }
+
+    /// Builds `Vec<PromptMessageRequest>` from `role: content` entries
+    /// and bare `complete` completion points
+    ///
+    /// ```
+    /// use promptpunch::prelude::*;
+    ///
+    /// let document = "Some text";
+    /// let messages = message::conversation! {
+    ///     system: "Act like a technical writer",
+    ///     user: format!("Summarize {document}"),
+    ///     complete,
+    ///     user: "Make it shorter",
+    /// };
+    /// assert_eq!(messages.len(), 4);
+    /// ```
+    #[macro_export]
+    macro_rules! conversation {
+        ($($body:tt)*) => {
+            $crate::__conversation!(@messages [] $($body)*)
+        };
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __conversation {
+        (@messages [$($messages:expr),*]) => {
+            ::std::vec![$($messages),*]
+        };
+        (@messages [$($messages:expr),*] complete $(, $($rest:tt)*)?) => {
+            $crate::__conversation!(@messages [$($messages,)* $crate::complete!()] $($($rest)*)?)
+        };
+        (@messages [$($messages:expr),*] system: $content:expr $(, $($rest:tt)*)?) => {
+            $crate::__conversation!(@messages [$($messages,)* $crate::system!($content)] $($($rest)*)?)
+        };
+        (@messages [$($messages:expr),*] user: $content:expr $(, $($rest:tt)*)?) => {
+            $crate::__conversation!(@messages [$($messages,)* $crate::user!($content)] $($($rest)*)?)
+        };
+        (@messages [$($messages:expr),*] assistant: $content:expr $(, $($rest:tt)*)?) => {
+            $crate::__conversation!(@messages [$($messages,)* $crate::assistant!($content)] $($($rest)*)?)
         };
     }
 
+    pub use assistant;
     pub use complete;
+    pub use conversation;
     pub use system;
     pub use user;
+
+    #[cfg(test)]
+    mod tests {
+        use crate::{PromptMessage, PromptMessageRequest, Role};
+
+        #[test]
+        fn builds_messages() {
+            let document = "Some text";
+            let messages = super::conversation! {
+                system: "Act like a {persona}",
+                user: format!("Summarize {document}"),
+                complete,
+                assistant: "Summary",
+                user: "Make it shorter"
+            };
+            assert_eq!(
+                messages,
+                vec![
+                    super::system!("Act like a {persona}"),
+                    super::user!("Summarize {}", document),
+                    super::complete!(),
+                    PromptMessageRequest::Message {
+                        body: PromptMessage {
+                            role: Role::Assistant,
+                            content: "Summary".to_string()
+                        }
+                    },
+                    super::user!("Make it {}", "shorter"),
+                ]
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use crate::prelude::*;
+
+    #[test]
+    fn builds_prompt_fluently() {
+        let prompt = PromptBuilder::default()
+            .system("Act like a Gandalf")
+            .user("How r you?")
+            .complete()
+            .user("Repeat ur words")
+            .complete_with(CompletionParams {
+                temperature: Some(0.0),
+                ..Default::default()
+            })
+            .extend_from_markdown(
+                "# User\nAnd now in {language}",
+                &[InjectableData::new("{language}", "Elvish")],
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            prompt.messages,
+            vec![
+                message::system!("Act like a Gandalf"),
+                message::user!("How r you?"),
+                message::complete!(),
+                message::user!("Repeat ur words"),
+                PromptMessageRequest::WaitCompletionWith {
+                    params: CompletionParams {
+                        temperature: Some(0.0),
+                        ..Default::default()
+                    }
+                },
+                message::user!("And now in Elvish"),
+            ]
+        );
+
+        assert!(PromptBuilder::default()
+            .system("Act like a Gandalf")
+            .complete()
+            .build()
+            .is_err());
+        assert!(PromptBuilder::default()
+            .user("How r you?")
+            .complete()
+            .complete()
+            .build()
+            .is_err());
+    }
+}
+
+#[cfg(all(test, feature = "macros"))]
+mod tests {
+    use crate::prelude::*;
+
+    crate::prompt!(Summarize, "examples/prompts/summarize.md");
+
+    #[test]
+    fn embeds_prompt() {
+        let prompt = Summarize {
+            document: "Some text".to_string(),
+            words: None,
+        }
+        .prompt()
+        .unwrap();
+        assert_eq!(prompt.temperature, 0.5);
+        assert_eq!(
+            prompt.messages,
+            vec![
+                message::system!("You are a concise technical writer"),
+                message::user!("Summarize the following text in 50 words\n\nSome text"),
+            ]
+        );
+
+        let prompt = Summarize {
+            document: "Some text".to_string(),
+            words: Some(10),
+        }
+        .prompt()
+        .unwrap();
+        assert_eq!(
+            prompt.messages[1],
+            message::user!("Summarize the following text in 10 words\n\nSome text")
+        );
+    }
+
+    #[derive(serde::Serialize)]
+    struct Document {
+        title: String,
+    }
+
+    #[derive(PromptInput)]
+    #[prompt(path = "examples/prompts/summarize.md")]
+    struct SummarizeInput {
+        #[prompt(json)]
+        document: Document,
+        words: Option<u32>,
+        #[prompt(skip)]
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    #[test]
+    fn derives_prompt_input() {
+        let input = SummarizeInput {
+            document: Document {
+                title: "Rust".to_string(),
+            },
+            words: Some(10),
+            id: 1,
+        };
+        assert_eq!(
+            SummarizeInput::placeholders(),
+            vec!["{document}".to_string(), "{words}".to_string()]
+        );
+        let prompt = input
+            .render(include_str!("../examples/prompts/summarize.md"))
+            .unwrap();
+        assert_eq!(
+            prompt.messages[1],
+            message::user!(
+                "Summarize the following text in 10 words\n\n{\n  \"title\": \"Rust\"\n}"
+            )
+        );
+        assert!(SummarizeInput::validate("# User\n{document}").is_err());
+    }
 }
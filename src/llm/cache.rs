@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    chat_gpt::count_tokens,
+    turns::{complete_turn, Deadline, Reply, Turns},
+    LlmProvider, TokenSink,
+};
+use crate::{Completion, Prompt, PromptMessage, PromptMessageRequest, Role};
+
+/// Stored reply of a single turn
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Serialized request, guards against hash collisions
+    pub key: String,
+    pub reply: PromptMessage,
+    /// See [`Completion::served_by`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub served_by: Vec<String>,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+}
+
+pub trait CacheStore: Send + Sync {
+    fn get(&self, key: &str) -> anyhow::Result<Option<CacheEntry>>;
+    fn put(&self, entry: CacheEntry) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl CacheStore for MemoryStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<CacheEntry>> {
+        let entries = self.entries.lock().expect("Cache lock is poisoned");
+        Ok(entries.get(key).cloned())
+    }
+
+    fn put(&self, entry: CacheEntry) -> anyhow::Result<()> {
+        let mut entries = self.entries.lock().expect("Cache lock is poisoned");
+        entries.insert(entry.key.clone(), entry);
+        Ok(())
+    }
+}
+
+/// JSON file per entry named by the hash of the request, can be committed for replay
+#[derive(Debug)]
+pub struct DiskStore {
+    dir: PathBuf,
+}
+
+impl DiskStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}.json", fnv1a(key.as_bytes())))
+    }
+}
+
+impl CacheStore for DiskStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<CacheEntry>> {
+        let path = self.path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read cache entry {}", path.display()))?;
+        let entry = serde_json::from_str::<CacheEntry>(&text)
+            .with_context(|| format!("Invalid cache entry {}", path.display()))?;
+        Ok((entry.key == key).then_some(entry))
+    }
+
+    fn put(&self, entry: CacheEntry) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create cache in {}", self.dir.display()))?;
+        let path = self.path(&entry.key);
+        std::fs::write(&path, serde_json::to_string_pretty(&entry)?)
+            .with_context(|| format!("Failed to write cache entry {}", path.display()))
+    }
+}
+
+/// Stable across builds unlike `DefaultHasher`
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Replies from the cache, requests and stores the missing ones
+    #[default]
+    ReadWrite,
+    /// Always requests and overwrites the cache
+    Record,
+    /// Replies only from the cache ignoring the TTL, a missing entry is an error
+    Replay,
+}
+
+/// Cache settings, see [`Cache::wrap`]
+#[derive(Clone)]
+pub struct Cache {
+    store: Arc<dyn CacheStore>,
+    mode: CacheMode,
+    ttl: Option<Duration>,
+    namespace: String,
+}
+
+impl Cache {
+    pub fn new(store: impl CacheStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            mode: CacheMode::default(),
+            ttl: None,
+            namespace: String::new(),
+        }
+    }
+
+    pub fn memory() -> Self {
+        Self::new(MemoryStore::default())
+    }
+
+    pub fn disk(dir: impl Into<PathBuf>) -> Self {
+        Self::new(DiskStore::new(dir))
+    }
+
+    pub fn with_mode(mut self, mode: CacheMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Older entries are requested again
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Part of every key besides [`LlmProvider::cache_identity`] of the wrapped provider,
+    /// e.g. a version of the prompts
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    pub fn wrap<P>(self, inner: P) -> Cached<P> {
+        Cached { inner, cache: self }
+    }
+
+    fn lookup(&self, key: &str) -> anyhow::Result<Option<Reply>> {
+        if self.mode == CacheMode::Record {
+            return Ok(None);
+        }
+        let Some(entry) = self.store.get(key)? else {
+            return Ok(None);
+        };
+        let expired = self.mode != CacheMode::Replay
+            && self
+                .ttl
+                .is_some_and(|ttl| now().saturating_sub(entry.created) > ttl.as_secs());
+        Ok((!expired).then_some(Reply {
+            message: entry.reply,
+            served_by: entry.served_by,
+        }))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Provider caching every turn separately, so a changed tail of a long chain
+/// requests only the turns after the change
+pub struct Cached<P> {
+    inner: P,
+    cache: Cache,
+}
+
+impl<P> Cached<P> {
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+impl<P: LlmProvider> Cached<P> {
+    async fn run_prompt(
+        &self,
+        prompt: &Prompt,
+        mut sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<Completion> {
+        let deadline = Deadline::start(prompt);
+        let mut turns = Turns::new(prompt);
+        while let Some((step, turn)) = turns.next_turn() {
+            let key = format!(
+                "{}{}{}",
+                self.cache.namespace,
+                self.inner.cache_identity(),
+                serde_json::to_string(turn)?
+            );
+            let reply = match self.cache.lookup(&key)? {
+                Some(reply) => {
+                    if let Some(sink) = sink.as_deref_mut() {
+                        sink(&reply.message.content);
+                    }
+                    Ok(reply)
+                }
+                None if self.cache.mode == CacheMode::Replay => Err(anyhow::anyhow!(
+                    "Replayed cache has no reply for the completion point {step}"
+                )),
+                None => self.request(turn, key, deadline, sink.as_deref_mut()).await,
+            };
+            match reply {
+                Ok(reply) => turns.reply(reply),
+                Err(error) => return Err(turns.fail(step, error).into()),
+            }
+        }
+        Ok(turns.finish())
+    }
+
+    async fn request(
+        &self,
+        turn: &Prompt,
+        key: String,
+        deadline: Deadline,
+        sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<Reply> {
+        let reply = complete_turn(&self.inner, turn, deadline, sink).await?;
+        self.cache.store.put(CacheEntry {
+            key,
+            reply: reply.message.clone(),
+            served_by: reply.served_by.clone(),
+            created: now(),
+        })?;
+        Ok(reply)
+    }
+}
+
+/// Completion of the finished turns of a prompt, usage counted locally
+pub(super) fn into_completion(requests: Vec<PromptMessageRequest>) -> Completion {
+    let messages = requests
+        .into_iter()
+        .filter_map(|request| match request {
+            PromptMessageRequest::Message { body } => Some(body),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let tokens = |assistant: bool| {
+        messages
+            .iter()
+            .filter(|message| (message.role == Role::Assistant) == assistant)
+            .map(|message| count_tokens(&message.content))
+            .sum()
+    };
+    Completion {
+        user_tokens: tokens(false),
+        assistant_tokens: tokens(true),
+        served_by: Vec::new(),
+        messages,
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for Cached<P> {
+    fn default_model(&self) -> Option<String> {
+        self.inner.default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        self.inner.cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, None).await
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, Some(sink)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{Cache, CacheMode};
+    use crate::{
+        prelude::*,
+        test_util::{Counter, TempDir},
+    };
+
+    fn chain(last: &str) -> Prompt {
+        PromptBuilder::default()
+            .user("One")
+            .complete()
+            .user(last)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn caches_every_turn() {
+        let dir = TempDir::new("cache");
+        let cached = Cache::disk(dir.path()).wrap(Counter::default());
+
+        let completion = cached.complete_chat(&chain("Two")).await.unwrap();
+        assert_eq!(completion.last_assistant_response().unwrap(), "reply 2");
+        let completion = cached.complete_chat(&chain("Three")).await.unwrap();
+        assert_eq!(completion.messages[1].content, "reply 1");
+        assert_eq!(completion.last_assistant_response().unwrap(), "reply 2");
+        assert_eq!(cached.inner().requests.load(Ordering::SeqCst), 3);
+
+        let replay = Cache::disk(dir.path())
+            .with_mode(CacheMode::Replay)
+            .wrap(Counter::default());
+        let mut streamed = String::new();
+        let completion = replay
+            .complete_chat_streaming(&chain("Two"), &mut |token: &str| streamed.push_str(token))
+            .await
+            .unwrap();
+        assert_eq!(completion.messages.len(), 4);
+        assert_eq!(streamed, "reply 1reply 2");
+        assert!(replay.complete_chat(&chain("Four")).await.is_err());
+        assert_eq!(replay.inner().requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn keys_by_provider() {
+        let cache = Cache::memory();
+        let mini = cache.clone().wrap(Counter {
+            model: "mini",
+            ..Default::default()
+        });
+        let large = cache.wrap(Counter {
+            model: "large",
+            ..Default::default()
+        });
+        mini.complete_chat(&chain("Two")).await.unwrap();
+        mini.complete_chat(&chain("Two")).await.unwrap();
+        large.complete_chat(&chain("Two")).await.unwrap();
+        assert_eq!(mini.inner().requests.load(Ordering::SeqCst), 2);
+        assert_eq!(large.inner().requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,57 +1,174 @@
-use super::LlmProvider;
-use crate::{Completion, Prompt, PromptMessage, Role};
+use super::{
+    error::{Interrupted, PartialCompletionError},
+    rate_limit::RateLimiter,
+    turns::with_final_completion,
+    LlmProvider, TokenSink,
+};
+use crate::{
+    Completion, CompletionParams, OutputFormat, Prompt, PromptMessage, PromptMessageRequest, Role,
+};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{borrow::Borrow, fmt::Display};
-use tiktoken_rs::p50k_base;
+use std::{
+    fmt::Display,
+    future::Future,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tiktoken_rs::{p50k_base, CoreBPE};
+
+pub mod batch;
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
 
 #[derive(Clone)]
 pub struct ChatGpt {
     api_token: String,
     pub model: ChatGptModel,
+    /// OpenAI compatible API root, e.g. `http://localhost:11434/v1`
+    pub base_url: String,
+    pub max_tokens: Option<u32>,
+    pub seed: Option<u64>,
+    /// Shared by the clones
+    rate_limiter: Option<Arc<RateLimiter>>,
     client: reqwest::Client,
 }
 
-impl ChatGpt {
-    pub fn from_env() -> Self {
-        let api_token = std::env::var("OPENAI_API_KEY").expect("Set OPENAI_API_TOKEN");
-        let mut client = reqwest::Client::builder();
-        if let Ok(proxy) = std::env::var("OPENAI_PROXY") {
-            log::info!("Creating ChatGPT client with proxy");
-            client =
-                client.proxy(reqwest::Proxy::all(proxy).expect("Falied to bind proxy to OpenAI"));
+/// HTTP timeouts of every request, `None` waits forever
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    /// Between two reads of the response, long enough for reasoning models to think
+    pub read: Option<Duration>,
+    /// Whole request including the response body
+    pub total: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(10)),
+            read: Some(Duration::from_secs(300)),
+            total: None,
         }
+    }
+}
+
+fn build_client(timeouts: &Timeouts) -> reqwest::Client {
+    let mut client = reqwest::Client::builder();
+    if let Ok(proxy) = std::env::var("OPENAI_PROXY") {
+        log::info!("Creating ChatGPT client with proxy");
+        client = client.proxy(reqwest::Proxy::all(proxy).expect("Falied to bind proxy to OpenAI"));
+    }
+    if let Some(connect) = timeouts.connect {
+        client = client.connect_timeout(connect);
+    }
+    if let Some(read) = timeouts.read {
+        client = client.read_timeout(read);
+    }
+    if let Some(total) = timeouts.total {
+        client = client.timeout(total);
+    }
+    client
+        .build()
+        .expect("Failed to create http client for ChatGPT")
+}
+
+impl ChatGpt {
+    /// Client of the OpenAI API with the given key
+    pub fn new(api_token: impl Into<String>) -> Self {
         Self {
-            api_token,
+            api_token: api_token.into(),
             model: ChatGptModel::default(),
-            client: client
-                .build()
-                .expect("Failed to create http client for ChatGPT"),
+            base_url: OPENAI_BASE_URL.to_string(),
+            max_tokens: None,
+            seed: None,
+            rate_limiter: None,
+            client: build_client(&Timeouts::default()),
         }
     }
 
+    /// Client with the key from `OPENAI_API_KEY` and the API root from `OPENAI_BASE_URL` if set
+    pub fn from_env() -> Self {
+        let api_token = std::env::var("OPENAI_API_KEY").expect("Set OPENAI_API_TOKEN");
+        let llm = Self::new(api_token);
+        match std::env::var("OPENAI_BASE_URL") {
+            Ok(base_url) => llm.with_base_url(base_url),
+            Err(_) => llm,
+        }
+    }
+
+    /// Replaces the HTTP client, the clones keep the previous one
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.client = build_client(&timeouts);
+        self
+    }
+
     pub fn with_model(mut self, model: ChatGptModel) -> Self {
         self.model = model;
         self
     }
 
-    async fn make_completion(&self, request: &mut ChatGptCompletionRequest) -> anyhow::Result<()> {
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Waits for the limiter budget before every completion request, the clones share it
+    pub fn with_rate_limiter(mut self, rate_limiter: impl Into<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = Some(rate_limiter.into());
+        self
+    }
+
+    fn api_request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.base_url, path))
+            .header("Authorization", format!("Bearer {}", self.api_token))
+    }
+
+    async fn make_completion(
+        &self,
+        request: &mut ChatGptCompletionRequest,
+        sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<()> {
+        request.stream = sink.is_some();
+        if let Some(rate_limiter) = &self.rate_limiter {
+            // Prompt tokens plus the most the response may take
+            let tokens = request
+                .messages
+                .iter()
+                .map(|message| count_tokens(&message.content))
+                .sum::<usize>()
+                + request.max_tokens.unwrap_or(0) as usize;
+            rate_limiter.acquire(&request.model, tokens).await;
+        }
         let response = self
-            .client
-            .post("https://api.openai.com/v1/chat/completions")
+            .api_request(reqwest::Method::POST, "/chat/completions")
             .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_token))
             .body(serde_json::to_string(&request)?)
             .send()
             .await?;
+        let response = ensure_ok(response).await?;
 
-        if response.status() != reqwest::StatusCode::OK {
-            anyhow::bail!(
-                "Request to OpenAI failed with HTTP status {} and returned {}",
-                response.status(),
-                response.text().await?
-            )
+        if let Some(sink) = sink {
+            let content = read_stream(response, sink).await?;
+            request.messages.push(ChatGptMessage {
+                role: "assistant".to_string(),
+                content,
+            });
+            return Ok(());
         }
 
         let response = response.json::<ChatGptCompletionResponse>().await?;
@@ -66,55 +183,213 @@ impl ChatGpt {
     }
 }
 
+impl ChatGpt {
+    async fn make_completion_with(
+        &self,
+        request: &mut ChatGptCompletionRequest,
+        params: &CompletionParams,
+        sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<()> {
+        let model = request.model.clone();
+        let temperature = request.temperature;
+        let response_format = request.response_format.clone();
+        request.apply(params);
+
+        let result = self.make_completion(request, sink).await;
+        request.model = model;
+        request.temperature = temperature;
+        request.response_format = response_format;
+        result
+    }
+}
+
 #[async_trait]
 impl LlmProvider for ChatGpt {
-    async fn complete_chat(
+    fn default_model(&self) -> Option<String> {
+        Some(self.model.to_string())
+    }
+
+    fn cache_identity(&self) -> String {
+        serde_json::json!({
+            "base_url": self.base_url,
+            "model": self.model.to_string(),
+            "max_tokens": self.max_tokens,
+            "seed": self.seed,
+        })
+        .to_string()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, None, std::future::pending()).await
+    }
+
+    async fn complete_chat_streaming(
         &self,
-        prompt: impl Borrow<Prompt> + std::marker::Send,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
     ) -> anyhow::Result<Completion> {
-        let mut request = ChatGptCompletionRequest {
-            model: self.model.to_string(),
+        self.run_prompt(prompt, Some(sink), std::future::pending())
+            .await
+    }
+}
+
+impl ChatGpt {
+    /// Request with the prompt parameters and without messages
+    fn request(&self, prompt: &Prompt) -> ChatGptCompletionRequest {
+        ChatGptCompletionRequest {
+            model: prompt
+                .model
+                .clone()
+                .unwrap_or_else(|| self.model.to_string()),
             messages: vec![],
-            temperature: prompt.borrow().temperature,
+            temperature: prompt.temperature,
+            response_format: ResponseFormat::new(prompt.output_format),
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+            stream: false,
+        }
+    }
+
+    async fn run_prompt(
+        &self,
+        prompt: &Prompt,
+        mut sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+        cancel: impl Future<Output = ()> + Send,
+    ) -> anyhow::Result<Completion> {
+        let timeout = prompt.timeout;
+        let interrupt = async move {
+            match timeout {
+                Some(timeout) => tokio::select! {
+                    _ = tokio::time::sleep(timeout) => Interrupted::TimedOut(timeout),
+                    _ = cancel => Interrupted::Cancelled,
+                },
+                None => {
+                    cancel.await;
+                    Interrupted::Cancelled
+                }
+            }
         };
+        let mut interrupt = std::pin::pin!(interrupt);
 
+        let mut request = self.request(prompt);
         let mut user_tokens = 0;
 
-        for message_request in &prompt.borrow().messages {
-            match message_request {
-                crate::PromptMessageRequest::Message { body } => {
-                    user_tokens += count_tokens(&body.content);
-                    request.messages.push(body.clone().into())
-                }
-                crate::PromptMessageRequest::WaitCompletion => {
-                    self.make_completion(&mut request).await?;
-                }
+        for (step, message_request) in with_final_completion(prompt).enumerate() {
+            if let PromptMessageRequest::Message { body } = message_request {
+                user_tokens += count_tokens(&body.content);
+                request.messages.push(body.clone().into());
+                continue;
+            }
+            let result = tokio::select! {
+                result = self.complete_step(&mut request, message_request, sink.as_deref_mut()) => result,
+                interrupted = &mut interrupt => Err(interrupted.into()),
+            };
+            if let Err(error) = result {
+                let completion = into_completion(request.messages, user_tokens);
+                return Err(PartialCompletionError::new(completion, step, error).into());
             }
         }
-        self.make_completion(&mut request).await?;
 
-        let messages = request
-            .messages
-            .into_iter()
-            .map(Into::<PromptMessage>::into)
-            .collect::<Vec<_>>();
-
-        let assistant_tokens = messages
-            .iter()
-            .filter(|msg| msg.role == Role::Assistant)
-            .map(|msg| count_tokens(&msg.content))
-            .sum();
-
-        Ok(Completion {
-            messages,
-            user_tokens,
-            assistant_tokens,
-        })
+        Ok(into_completion(request.messages, user_tokens))
+    }
+
+    async fn complete_step(
+        &self,
+        request: &mut ChatGptCompletionRequest,
+        step: &PromptMessageRequest,
+        sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<()> {
+        match step {
+            PromptMessageRequest::WaitCompletionWith { params } => {
+                self.make_completion_with(request, params, sink).await
+            }
+            _ => self.make_completion(request, sink).await,
+        }
+    }
+
+    /// Same as [`LlmProvider::complete_chat`] stopping when `cancel` resolves,
+    /// stopped completion fails with [`PartialCompletionError`] as any failed turn does.
+    ///
+    /// Dropping the returned future is safe as well, only the finished turns are lost.
+    pub async fn complete_chat_until(
+        &self,
+        prompt: &Prompt,
+        cancel: impl Future<Output = ()> + Send,
+    ) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, None, cancel).await
     }
 }
 
+fn into_completion(messages: Vec<ChatGptMessage>, user_tokens: usize) -> Completion {
+    let messages = messages
+        .into_iter()
+        .map(Into::<PromptMessage>::into)
+        .collect::<Vec<_>>();
+
+    let assistant_tokens = messages
+        .iter()
+        .filter(|msg| msg.role == Role::Assistant)
+        .map(|msg| count_tokens(&msg.content))
+        .sum();
+
+    Completion {
+        messages,
+        user_tokens,
+        assistant_tokens,
+        served_by: Vec::new(),
+    }
+}
+
+async fn ensure_ok(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    if response.status() != reqwest::StatusCode::OK {
+        anyhow::bail!(
+            "Request to OpenAI failed with HTTP status {} and returned {}",
+            response.status(),
+            response.text().await?
+        )
+    }
+    Ok(response)
+}
+
+/// Reads server-sent events of the streamed completion, returns the whole response
+async fn read_stream(
+    mut response: reqwest::Response,
+    sink: &mut (dyn FnMut(&str) + Send + '_),
+) -> anyhow::Result<String> {
+    let mut content = String::new();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<_>>();
+            let line = String::from_utf8(line)?;
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok(content);
+            }
+            let chunk = serde_json::from_str::<ChatGptStreamChunk>(data)?;
+            if let Some(delta) = chunk
+                .choices
+                .into_iter()
+                .next()
+                .and_then(|choice| choice.delta.content)
+            {
+                sink(&delta);
+                content += &delta;
+            }
+        }
+    }
+    Ok(content)
+}
+
+/// Built once, building the tokenizer takes longer than most encodings
+static TOKENIZER: OnceLock<CoreBPE> = OnceLock::new();
+
 pub fn count_tokens(input: impl AsRef<str>) -> usize {
-    let tokenizer = p50k_base().expect("Failed to initialize tokenizer");
+    let tokenizer = TOKENIZER.get_or_init(|| p50k_base().expect("Failed to initialize tokenizer"));
     let tokens = tokenizer.encode_with_special_tokens(input.as_ref());
     tokens.len()
 }
@@ -169,6 +444,45 @@ struct ChatGptCompletionRequest {
     model: String,
     messages: Vec<ChatGptMessage>,
     temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+impl ChatGptCompletionRequest {
+    fn apply(&mut self, params: &CompletionParams) {
+        if let Some(model) = &params.model {
+            self.model = model.clone();
+        }
+        if let Some(temperature) = params.temperature {
+            self.temperature = temperature;
+        }
+        if let Some(output_format) = params.output_format {
+            self.response_format = ResponseFormat::new(output_format);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl ResponseFormat {
+    fn new(output_format: OutputFormat) -> Option<Self> {
+        match output_format {
+            OutputFormat::Text => None,
+            OutputFormat::Json => Some(ResponseFormat {
+                kind: "json_object".to_string(),
+            }),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -187,6 +501,21 @@ struct ChatGptCompletionResponse {
     choices: Vec<Choice>,
 }
 
+#[derive(Debug, Deserialize)]
+struct ChatGptStreamChunk {
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Usage {
@@ -223,7 +552,7 @@ struct Choice {
     index: i64,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub enum ChatGptModel {
     /// Context window - 128,000
     /// Max output - 32,768
@@ -247,6 +576,9 @@ pub enum ChatGptModel {
     /// Context window - 16,385
     /// Max output - 4,096
     Turbo35,
+    /// Any other model name, e.g. a fine-tune or a model of a compatible server,
+    /// context window is assumed to be 128,000
+    Custom(String),
 }
 
 impl ChatGptModel {
@@ -260,7 +592,29 @@ impl ChatGptModel {
             Turbo4 => 128_000,
             Just4 => 8_192,
             Turbo35 => 16_385,
+            Custom(_) => 128_000,
+        }
+    }
+
+    pub fn known() -> [ChatGptModel; 7] {
+        use ChatGptModel::*;
+        [O1Preview, O1Mini, Latest4o, Mini4o, Turbo4, Just4, Turbo35]
+    }
+}
+
+impl FromStr for ChatGptModel {
+    type Err = anyhow::Error;
+
+    /// Unknown names are [`ChatGptModel::Custom`]
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.trim();
+        if name.is_empty() {
+            anyhow::bail!("Model name is empty");
         }
+        Ok(Self::known()
+            .into_iter()
+            .find(|model| model.to_string() == name)
+            .unwrap_or_else(|| ChatGptModel::Custom(name.to_string())))
     }
 }
 
@@ -274,7 +628,142 @@ impl Display for ChatGptModel {
             ChatGptModel::Turbo4 => "gpt-4-turbo",
             ChatGptModel::Just4 => "gpt-4",
             ChatGptModel::Turbo35 => "gpt-3.5-turbo",
+            ChatGptModel::Custom(name) => name,
         };
         write!(f, "{}", str)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::time::Duration;
+
+    use super::{ChatGpt, ChatGptModel};
+    use crate::{
+        llm::{
+            error::{Interrupted, PartialCompletionError},
+            layer::Retry,
+        },
+        prelude::*,
+    };
+
+    /// Serves the responses in order on a local port, `None` never responds
+    fn serve(responses: Vec<Option<&'static str>>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+            for response in responses {
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                reader
+                    .by_ref()
+                    .take(content_length)
+                    .read_to_end(&mut vec![])
+                    .unwrap();
+                let Some(body) = response else {
+                    std::thread::sleep(Duration::from_secs(60));
+                    return;
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        format!("http://{address}")
+    }
+
+    const REPLY: &str = r#"{"id":"c1","object":"chat.completion","created":1,"model":"gpt-4o","usage":{},"choices":[{"index":0,"message":{"role":"assistant","content":"Hi"},"logprobs":null,"finish_reason":"stop"}]}"#;
+
+    fn llm(base_url: String) -> ChatGpt {
+        ChatGpt::new("test").with_base_url(base_url)
+    }
+
+    #[tokio::test]
+    async fn keeps_finished_turns_on_timeout() {
+        let llm = llm(serve(vec![Some(REPLY), None]));
+        let prompt = PromptBuilder::default()
+            .user("One")
+            .complete()
+            .user("Two")
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let error = llm.complete_chat(&prompt).await.unwrap_err();
+        let error = error.downcast::<PartialCompletionError>().unwrap();
+        assert_eq!(
+            error.interrupted(),
+            Some(Interrupted::TimedOut(Duration::from_millis(500)))
+        );
+        assert_eq!(error.completion.messages.len(), 3);
+        assert_eq!(error.completion.last_assistant_response().unwrap(), "Hi");
+    }
+
+    #[tokio::test]
+    async fn resumes_failed_turn() {
+        let prompt = PromptBuilder::default()
+            .system("Greet")
+            .user("One")
+            .complete()
+            .user("Two")
+            .complete()
+            .user("Three")
+            .build()
+            .unwrap();
+        let error = llm(serve(vec![Some(REPLY), Some("Bad gateway")]))
+            .complete_chat(&prompt)
+            .await
+            .unwrap_err();
+        let error = error.downcast::<PartialCompletionError>().unwrap();
+        assert_eq!(error.interrupted(), None);
+        assert_eq!(error.step, 4);
+        assert_eq!(error.completion.messages.len(), 4);
+
+        let resumed = error.resume(&prompt);
+        assert_eq!(resumed.messages.len(), 6);
+        let completion = llm(serve(vec![Some(REPLY), Some(REPLY)]))
+            .complete_chat(&resumed)
+            .await
+            .unwrap();
+        assert_eq!(completion.messages.len(), 7);
+    }
+
+    #[test]
+    fn tells_default_model() {
+        let llm: Box<dyn LlmProvider> = Box::new(
+            llm("http://localhost".to_string())
+                .with_model(ChatGptModel::Mini4o)
+                .layer(Retry::default()),
+        );
+        assert_eq!(llm.default_model(), Some(ChatGptModel::Mini4o.to_string()));
+    }
+
+    #[test]
+    fn parses_models() {
+        for model in ChatGptModel::known() {
+            assert_eq!(model.to_string().parse::<ChatGptModel>().unwrap(), model);
+        }
+        assert_eq!(
+            "ft:gpt-4o-mini:acme".parse::<ChatGptModel>().unwrap(),
+            ChatGptModel::Custom("ft:gpt-4o-mini:acme".to_string())
+        );
+        assert!("".parse::<ChatGptModel>().is_err());
+    }
+}
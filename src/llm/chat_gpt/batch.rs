@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    count_tokens, ensure_ok, ChatGpt, ChatGptCompletionRequest, ChatGptCompletionResponse,
+};
+use crate::{Completion, Prompt, PromptMessage, PromptMessageRequest, Role};
+
+/// Prompts completed offline by the OpenAI Batch API at half the price,
+/// results are usually ready within hours and at most within 24 hours.
+///
+/// Only single-turn prompts are supported, a completion point is allowed
+/// only at the end and its parameters apply to the request.
+#[derive(Debug, Clone, Default)]
+pub struct OpenAiBatch {
+    prompts: BTreeMap<String, Prompt>,
+}
+
+/// Line of the batch input file
+#[derive(Debug, Serialize)]
+struct BatchRequestLine<'a> {
+    custom_id: &'a str,
+    method: &'static str,
+    url: &'static str,
+    body: ChatGptCompletionRequest,
+}
+
+/// Line of the batch output or error file
+#[derive(Debug, Deserialize)]
+struct BatchResponseLine {
+    custom_id: String,
+    response: Option<BatchResponse>,
+    error: Option<BatchError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    status_code: u16,
+    body: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadedFile {
+    id: String,
+}
+
+/// Batch as reported by the API
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchJob {
+    pub id: String,
+    /// `validating`, `in_progress`, `finalizing`, `completed`, `failed`, `expired`,
+    /// `cancelling` or `cancelled`
+    pub status: String,
+    pub output_file_id: Option<String>,
+    pub error_file_id: Option<String>,
+    pub request_counts: Option<BatchRequestCounts>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchRequestCounts {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+impl BatchJob {
+    /// The batch will not change anymore
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status.as_str(),
+            "completed" | "failed" | "expired" | "cancelled"
+        )
+    }
+}
+
+impl OpenAiBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds prompt under the id its completion is returned by,
+    /// fails for duplicate ids and multi-turn prompts
+    pub fn add(
+        &mut self,
+        custom_id: impl Into<String>,
+        prompt: Prompt,
+    ) -> anyhow::Result<&mut Self> {
+        let custom_id = custom_id.into();
+        let turns = prompt
+            .messages
+            .iter()
+            .filter(|request| !matches!(request, PromptMessageRequest::Message { .. }))
+            .count();
+        let ends_with_completion = !matches!(
+            prompt.messages.last(),
+            Some(PromptMessageRequest::Message { .. })
+        );
+        if turns > 1 || (turns == 1 && !ends_with_completion) {
+            anyhow::bail!(
+                "Prompt {custom_id} waits for completion in the middle, \
+                 batch supports single-turn prompts only"
+            );
+        }
+        if self.prompts.contains_key(&custom_id) {
+            anyhow::bail!("Prompt {custom_id} is already in the batch");
+        }
+        self.prompts.insert(custom_id, prompt);
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.prompts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prompts.is_empty()
+    }
+
+    /// Batch input file with a chat completion request per line
+    pub fn to_jsonl(&self, llm: &ChatGpt) -> anyhow::Result<String> {
+        let mut jsonl = String::new();
+        for (custom_id, prompt) in &self.prompts {
+            let mut body = llm.request(prompt);
+            for request in &prompt.messages {
+                match request {
+                    PromptMessageRequest::Message { body: message } => {
+                        body.messages.push(message.clone().into())
+                    }
+                    PromptMessageRequest::WaitCompletion => {}
+                    PromptMessageRequest::WaitCompletionWith { params } => body.apply(params),
+                }
+            }
+            let line = BatchRequestLine {
+                custom_id,
+                method: "POST",
+                url: "/v1/chat/completions",
+                body,
+            };
+            jsonl += &serde_json::to_string(&line)?;
+            jsonl.push('\n');
+        }
+        Ok(jsonl)
+    }
+
+    /// Uploads the input file and creates the batch
+    pub async fn submit(&self, llm: &ChatGpt) -> anyhow::Result<BatchJob> {
+        if self.is_empty() {
+            anyhow::bail!("Batch has no prompts");
+        }
+        let file = reqwest::multipart::Part::text(self.to_jsonl(llm)?)
+            .file_name("batch.jsonl")
+            .mime_str("application/jsonl")?;
+        let form = reqwest::multipart::Form::new()
+            .text("purpose", "batch")
+            .part("file", file);
+        let response = llm
+            .api_request(reqwest::Method::POST, "/files")
+            .multipart(form)
+            .send()
+            .await?;
+        let file = ensure_ok(response).await?.json::<UploadedFile>().await?;
+
+        let response = llm
+            .api_request(reqwest::Method::POST, "/batches")
+            .json(&serde_json::json!({
+                "input_file_id": file.id,
+                "endpoint": "/v1/chat/completions",
+                "completion_window": "24h",
+            }))
+            .send()
+            .await?;
+        Ok(ensure_ok(response).await?.json::<BatchJob>().await?)
+    }
+
+    /// Completions by custom id, the requests failed by the API are errors
+    pub async fn results(
+        &self,
+        llm: &ChatGpt,
+        job: &BatchJob,
+    ) -> anyhow::Result<BTreeMap<String, anyhow::Result<Completion>>> {
+        let mut jsonl = String::new();
+        for file_id in [&job.output_file_id, &job.error_file_id]
+            .into_iter()
+            .flatten()
+        {
+            jsonl += &llm.download_file(file_id).await?;
+            jsonl.push('\n');
+        }
+        self.completions(&jsonl)
+    }
+
+    /// Submits the batch, waits for it to finish and returns the completions by custom id
+    pub async fn run(
+        &self,
+        llm: &ChatGpt,
+        poll_interval: Duration,
+    ) -> anyhow::Result<BTreeMap<String, anyhow::Result<Completion>>> {
+        let job = self.submit(llm).await?;
+        log::info!("Submitted batch {} of {} prompts", job.id, self.len());
+        let job = llm.wait_batch(&job.id, poll_interval).await?;
+        if job.status != "completed" {
+            anyhow::bail!("Batch {} is {}", job.id, job.status);
+        }
+        self.results(llm, &job).await
+    }
+
+    /// Maps lines of the output and error files to the completions of the prompts,
+    /// prompts missing in the output are errors
+    pub fn completions(
+        &self,
+        output_jsonl: &str,
+    ) -> anyhow::Result<BTreeMap<String, anyhow::Result<Completion>>> {
+        let mut completions = BTreeMap::new();
+        for line in output_jsonl.lines().filter(|line| !line.trim().is_empty()) {
+            let line = serde_json::from_str::<BatchResponseLine>(line)
+                .context("Invalid batch output line")?;
+            let prompt = self
+                .prompts
+                .get(&line.custom_id)
+                .with_context(|| format!("Batch output has unknown id {}", line.custom_id))?;
+            completions.insert(line.custom_id.clone(), completion(prompt, line));
+        }
+        for custom_id in self.prompts.keys() {
+            if !completions.contains_key(custom_id) {
+                completions.insert(
+                    custom_id.clone(),
+                    Err(anyhow::anyhow!(
+                        "Batch output has no result for {custom_id}"
+                    )),
+                );
+            }
+        }
+        Ok(completions)
+    }
+}
+
+fn completion(prompt: &Prompt, line: BatchResponseLine) -> anyhow::Result<Completion> {
+    if let Some(error) = line.error {
+        anyhow::bail!("Batch request failed: {}", error.message);
+    }
+    let response = line.response.context("Batch result has no response")?;
+    if response.status_code != 200 {
+        anyhow::bail!(
+            "Batch request failed with HTTP status {} and returned {}",
+            response.status_code,
+            response.body
+        );
+    }
+    let response = serde_json::from_value::<ChatGptCompletionResponse>(response.body)?;
+    let reply = response
+        .choices
+        .into_iter()
+        .next()
+        .context("Batch response has no choices")?
+        .message;
+
+    let mut messages = prompt
+        .messages
+        .iter()
+        .filter_map(|request| match request {
+            PromptMessageRequest::Message { body } => Some(body.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let user_tokens = messages
+        .iter()
+        .map(|message| count_tokens(&message.content))
+        .sum();
+    messages.push(PromptMessage::from(reply));
+    let assistant_tokens = messages
+        .iter()
+        .filter(|message| message.role == Role::Assistant)
+        .map(|message| count_tokens(&message.content))
+        .sum();
+    Ok(Completion {
+        messages,
+        user_tokens,
+        assistant_tokens,
+        served_by: Vec::new(),
+    })
+}
+
+impl ChatGpt {
+    pub async fn batch_status(&self, batch_id: &str) -> anyhow::Result<BatchJob> {
+        let response = self
+            .api_request(reqwest::Method::GET, &format!("/batches/{batch_id}"))
+            .send()
+            .await?;
+        Ok(ensure_ok(response).await?.json::<BatchJob>().await?)
+    }
+
+    /// Polls the batch until it is finished
+    pub async fn wait_batch(
+        &self,
+        batch_id: &str,
+        poll_interval: Duration,
+    ) -> anyhow::Result<BatchJob> {
+        loop {
+            let job = self.batch_status(batch_id).await?;
+            if job.is_finished() {
+                return Ok(job);
+            }
+            if let Some(counts) = &job.request_counts {
+                log::info!(
+                    "Batch {} is {}, {} of {} requests done",
+                    job.id,
+                    job.status,
+                    counts.completed + counts.failed,
+                    counts.total
+                );
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    pub async fn cancel_batch(&self, batch_id: &str) -> anyhow::Result<BatchJob> {
+        let response = self
+            .api_request(
+                reqwest::Method::POST,
+                &format!("/batches/{batch_id}/cancel"),
+            )
+            .send()
+            .await?;
+        Ok(ensure_ok(response).await?.json::<BatchJob>().await?)
+    }
+
+    async fn download_file(&self, file_id: &str) -> anyhow::Result<String> {
+        let response = self
+            .api_request(reqwest::Method::GET, &format!("/files/{file_id}/content"))
+            .send()
+            .await?;
+        Ok(ensure_ok(response).await?.text().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpenAiBatch;
+    use crate::{llm::chat_gpt::ChatGpt, prelude::*};
+
+    fn llm() -> ChatGpt {
+        ChatGpt::new("test")
+    }
+
+    #[test]
+    fn maps_batch_results() {
+        let mut batch = OpenAiBatch::new();
+        let prompt = |text: &str| PromptBuilder::default().user(text).build().unwrap();
+        batch.add("a", prompt("Hello")).unwrap();
+        batch
+            .add(
+                "b",
+                PromptBuilder::default()
+                    .user("Json please")
+                    .complete_with(CompletionParams {
+                        output_format: Some(OutputFormat::Json),
+                        ..Default::default()
+                    })
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        assert!(batch.add("a", prompt("Again")).is_err());
+        let multi_turn = PromptBuilder::default()
+            .user("One")
+            .complete()
+            .user("Two")
+            .build()
+            .unwrap();
+        assert!(batch.add("c", multi_turn).is_err());
+
+        let jsonl = batch.to_jsonl(&llm()).unwrap();
+        let lines = jsonl
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines[0]["custom_id"], "a");
+        assert_eq!(lines[0]["body"]["messages"][0]["content"], "Hello");
+        assert_eq!(lines[1]["body"]["response_format"]["type"], "json_object");
+
+        let output = r#"{"id":"r1","custom_id":"a","response":{"status_code":200,"body":{"id":"c1","object":"chat.completion","created":1,"model":"gpt-4o","usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2},"choices":[{"index":0,"message":{"role":"assistant","content":"Hi"},"logprobs":null,"finish_reason":"stop"}]}},"error":null}"#;
+        let completions = batch.completions(output).unwrap();
+        let completion = completions["a"].as_ref().unwrap();
+        assert_eq!(completion.last_assistant_response().unwrap(), "Hi");
+        assert_eq!(completion.messages.len(), 2);
+        assert!(completions["b"].is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::{Completion, Prompt, PromptMessageRequest, Role};
+
+/// Reason a completion was stopped from outside
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupted {
+    /// [`crate::Prompt::timeout`] elapsed
+    TimedOut(Duration),
+    Cancelled,
+}
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interrupted::TimedOut(timeout) => write!(f, "Prompt timed out after {timeout:?}"),
+            Interrupted::Cancelled => write!(f, "Prompt was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Completion stopped before all the turns finished,
+/// get it with `anyhow::Error::downcast::<PartialCompletionError>()`
+#[derive(Debug)]
+pub struct PartialCompletionError {
+    /// Messages and usage of the turns finished before the error
+    pub completion: Completion,
+    /// Index of the failed completion point in [`Prompt::messages`],
+    /// their length for the completion at the end of the prompt
+    pub step: usize,
+    pub error: anyhow::Error,
+}
+
+impl PartialCompletionError {
+    pub fn new(completion: Completion, step: usize, error: anyhow::Error) -> Self {
+        Self {
+            completion,
+            step,
+            error,
+        }
+    }
+
+    /// The prompt with the finished turns as messages, continues from the failed step
+    pub fn resume(&self, prompt: &Prompt) -> Prompt {
+        let mut resumed = prompt.clone();
+        resumed.messages = self
+            .completion
+            .messages
+            .iter()
+            .cloned()
+            .map(|body| PromptMessageRequest::Message { body })
+            .chain(prompt.messages.iter().skip(self.step).cloned())
+            .collect();
+        resumed
+    }
+
+    /// The error is a timeout or cancellation rather than a failed request
+    pub fn interrupted(&self) -> Option<Interrupted> {
+        self.error.downcast_ref::<Interrupted>().copied()
+    }
+}
+
+impl Display for PartialCompletionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let finished = self
+            .completion
+            .messages
+            .iter()
+            .filter(|message| message.role == Role::Assistant)
+            .count();
+        write!(
+            f,
+            "Completion stopped with {finished} assistant messages: {}",
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for PartialCompletionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
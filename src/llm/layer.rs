@@ -0,0 +1,588 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use super::{
+    cache::{Cache, Cached},
+    chat_gpt::count_tokens,
+    error::{Interrupted, PartialCompletionError},
+    rate_limit::RateLimiter,
+    turns::{complete_turn, Deadline, Turns},
+    LlmProvider, TokenSink,
+};
+use crate::{Completion, Prompt, PromptMessageRequest};
+
+/// Wraps a provider into another one adding a concern such as retries or caching,
+/// apply with [`LlmProvider::layer`]
+pub trait Layer<P> {
+    type Provider: LlmProvider;
+
+    fn layer(self, inner: P) -> Self::Provider;
+}
+
+impl<P: LlmProvider> Layer<P> for Cache {
+    type Provider = Cached<P>;
+
+    fn layer(self, inner: P) -> Self::Provider {
+        self.wrap(inner)
+    }
+}
+
+/// Retries failed completions with exponential backoff,
+/// a multi-turn prompt resumes from the failed turn.
+///
+/// Attempts share [`Prompt::timeout`], no retry is made when the backoff would exceed it.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// Including the first one
+    pub attempts: usize,
+    /// Delay before the first retry, doubled before every next one
+    pub backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+pub struct Retrying<P> {
+    inner: P,
+    retry: Retry,
+}
+
+impl<P: LlmProvider> Layer<P> for Retry {
+    type Provider = Retrying<P>;
+
+    fn layer(self, inner: P) -> Self::Provider {
+        Retrying { inner, retry: self }
+    }
+}
+
+impl<P: LlmProvider> Retrying<P> {
+    async fn run_prompt(
+        &self,
+        prompt: &Prompt,
+        mut sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<Completion> {
+        let deadline = Deadline::start(prompt);
+        let mut prompt = prompt.clone();
+        let mut backoff = self.retry.backoff;
+        let mut attempt = 1;
+        // Backends of the turns finished by the earlier attempts
+        let mut served_by = vec![];
+        let result = loop {
+            let request = match deadline.limit(&prompt) {
+                Ok(request) => request,
+                Err(error) => break Err(error),
+            };
+            let result = match sink.as_deref_mut() {
+                Some(sink) => self.inner.complete_chat_streaming(&request, sink).await,
+                None => self.inner.complete_chat(&request).await,
+            };
+            let error = match result {
+                Ok(completion) => break Ok(completion),
+                Err(error) => error,
+            };
+            let partial = error.downcast_ref::<PartialCompletionError>();
+            // Timeouts and cancellations are up to the caller
+            let interrupted = error.chain().any(|cause| cause.is::<Interrupted>());
+            if attempt >= self.retry.attempts || interrupted {
+                break Err(error);
+            }
+            if deadline.left().is_some_and(|left| left <= backoff) {
+                break Err(deadline.time_out(error));
+            }
+            if let Some(partial) = partial {
+                prompt = partial.resume(&prompt);
+                served_by.extend(partial.completion.served_by.iter().cloned());
+            }
+            log::warn!("Completion attempt {attempt} failed, retrying in {backoff:?}: {error:#}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        };
+        match result {
+            Ok(mut completion) => {
+                served_by.append(&mut completion.served_by);
+                completion.served_by = served_by;
+                Ok(completion)
+            }
+            Err(error) => match error.downcast::<PartialCompletionError>() {
+                Ok(mut partial) => {
+                    served_by.append(&mut partial.completion.served_by);
+                    partial.completion.served_by = served_by;
+                    Err(partial.into())
+                }
+                Err(error) => Err(error),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for Retrying<P> {
+    fn default_model(&self) -> Option<String> {
+        self.inner.default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        self.inner.cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, None).await
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, Some(sink)).await
+    }
+}
+
+/// Logs every completion with its duration and token usage
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    /// Prefix of the log lines telling the providers apart
+    pub name: String,
+}
+
+pub struct Logged<P> {
+    inner: P,
+    name: String,
+}
+
+impl<P: LlmProvider> Layer<P> for Log {
+    type Provider = Logged<P>;
+
+    fn layer(self, inner: P) -> Self::Provider {
+        Logged {
+            inner,
+            name: self.name,
+        }
+    }
+}
+
+impl<P> Logged<P> {
+    fn log(&self, prompt: &Prompt, started: Instant, result: &anyhow::Result<Completion>) {
+        let elapsed = started.elapsed();
+        match result {
+            Ok(completion) => log::info!(
+                "{}Completed prompt of {} messages in {elapsed:?} using {} user and {} assistant tokens",
+                self.name,
+                prompt.messages.len(),
+                completion.user_tokens,
+                completion.assistant_tokens
+            ),
+            Err(error) => log::warn!(
+                "{}Failed prompt of {} messages in {elapsed:?}: {error:#}",
+                self.name,
+                prompt.messages.len()
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for Logged<P> {
+    fn default_model(&self) -> Option<String> {
+        self.inner.default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        self.inner.cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        let started = Instant::now();
+        let result = self.inner.complete_chat(prompt).await;
+        self.log(prompt, started, &result);
+        result
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        let started = Instant::now();
+        let result = self.inner.complete_chat_streaming(prompt, sink).await;
+        self.log(prompt, started, &result);
+        result
+    }
+}
+
+/// Token usage summed over every completion of the providers sharing it,
+/// failed prompts count their finished turns
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    totals: Arc<UsageTotals>,
+}
+
+#[derive(Debug, Default)]
+struct UsageTotals {
+    prompts: AtomicUsize,
+    user_tokens: AtomicUsize,
+    assistant_tokens: AtomicUsize,
+}
+
+impl Usage {
+    pub fn prompts(&self) -> usize {
+        self.totals.prompts.load(Ordering::Relaxed)
+    }
+
+    pub fn user_tokens(&self) -> usize {
+        self.totals.user_tokens.load(Ordering::Relaxed)
+    }
+
+    pub fn assistant_tokens(&self) -> usize {
+        self.totals.assistant_tokens.load(Ordering::Relaxed)
+    }
+
+    /// Cost given the prices of a million tokens
+    pub fn cost(&self, user_price: f64, assistant_price: f64) -> f64 {
+        (self.user_tokens() as f64 * user_price + self.assistant_tokens() as f64 * assistant_price)
+            / 1_000_000.0
+    }
+
+    fn record(&self, result: &anyhow::Result<Completion>) {
+        let completion = match result {
+            Ok(completion) => completion,
+            Err(error) => match error.downcast_ref::<PartialCompletionError>() {
+                Some(partial) => &partial.completion,
+                None => return,
+            },
+        };
+        let totals = &self.totals;
+        totals.prompts.fetch_add(1, Ordering::Relaxed);
+        totals
+            .user_tokens
+            .fetch_add(completion.user_tokens, Ordering::Relaxed);
+        totals
+            .assistant_tokens
+            .fetch_add(completion.assistant_tokens, Ordering::Relaxed);
+    }
+}
+
+pub struct Metered<P> {
+    inner: P,
+    usage: Usage,
+}
+
+impl<P: LlmProvider> Layer<P> for Usage {
+    type Provider = Metered<P>;
+
+    fn layer(self, inner: P) -> Self::Provider {
+        Metered { inner, usage: self }
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for Metered<P> {
+    fn default_model(&self) -> Option<String> {
+        self.inner.default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        self.inner.cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        let result = self.inner.complete_chat(prompt).await;
+        self.usage.record(&result);
+        result
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        let result = self.inner.complete_chat_streaming(prompt, sink).await;
+        self.usage.record(&result);
+        result
+    }
+}
+
+/// Rewrites message contents before they leave the process, e.g. to mask secrets,
+/// the completion keeps the rewritten messages
+#[derive(Clone)]
+pub struct Redact {
+    redact: Arc<dyn Fn(&str) -> String + Send + Sync>,
+}
+
+impl Redact {
+    pub fn new(redact: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        Self {
+            redact: Arc::new(redact),
+        }
+    }
+
+    fn apply(&self, prompt: &Prompt) -> Prompt {
+        let mut prompt = prompt.clone();
+        for request in &mut prompt.messages {
+            if let PromptMessageRequest::Message { body } = request {
+                body.content = (self.redact)(&body.content);
+            }
+        }
+        prompt
+    }
+}
+
+pub struct Redacted<P> {
+    inner: P,
+    redact: Redact,
+}
+
+impl<P: LlmProvider> Layer<P> for Redact {
+    type Provider = Redacted<P>;
+
+    fn layer(self, inner: P) -> Self::Provider {
+        Redacted {
+            inner,
+            redact: self,
+        }
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for Redacted<P> {
+    fn default_model(&self) -> Option<String> {
+        self.inner.default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        self.inner.cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        let prompt = self.redact.apply(prompt);
+        self.inner.complete_chat(&prompt).await
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        let prompt = self.redact.apply(prompt);
+        self.inner.complete_chat_streaming(&prompt, sink).await
+    }
+}
+
+/// Waits for the budget of the model before every completion request,
+/// a multi-turn prompt is sent a turn at a time like [`Cached`] does.
+/// Requests without model use the limit of [`LlmProvider::default_model`],
+/// the `""` model limit when the provider does not tell it.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: impl Into<Arc<RateLimiter>>) -> Self {
+        Self {
+            limiter: limiter.into(),
+        }
+    }
+
+    /// Budget of a turn ending with its completion point
+    async fn acquire(&self, turn: &Prompt, default_model: Option<String>) {
+        let mut tokens = 0;
+        let mut model = turn.model.as_deref().or(default_model.as_deref());
+        for request in &turn.messages {
+            match request {
+                PromptMessageRequest::Message { body } => tokens += count_tokens(&body.content),
+                PromptMessageRequest::WaitCompletionWith { params } if params.model.is_some() => {
+                    model = params.model.as_deref()
+                }
+                _ => {}
+            }
+        }
+        self.limiter
+            .acquire(model.unwrap_or_default(), tokens)
+            .await;
+    }
+}
+
+pub struct RateLimited<P> {
+    inner: P,
+    rate_limit: RateLimitLayer,
+}
+
+impl<P: LlmProvider> RateLimited<P> {
+    async fn run_prompt(
+        &self,
+        prompt: &Prompt,
+        mut sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<Completion> {
+        let deadline = Deadline::start(prompt);
+        let mut turns = Turns::new(prompt);
+        while let Some((step, turn)) = turns.next_turn() {
+            let default_model = self.inner.default_model();
+            self.rate_limit.acquire(turn, default_model).await;
+            match complete_turn(&self.inner, turn, deadline, sink.as_deref_mut()).await {
+                Ok(reply) => turns.reply(reply),
+                Err(error) => return Err(turns.fail(step, error).into()),
+            }
+        }
+        Ok(turns.finish())
+    }
+}
+
+impl<P: LlmProvider> Layer<P> for RateLimitLayer {
+    type Provider = RateLimited<P>;
+
+    fn layer(self, inner: P) -> Self::Provider {
+        RateLimited {
+            inner,
+            rate_limit: self,
+        }
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider> LlmProvider for RateLimited<P> {
+    fn default_model(&self) -> Option<String> {
+        self.inner.default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        self.inner.cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, None).await
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, Some(sink)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{RateLimitLayer, Redact, Retry, Usage};
+    use crate::{
+        llm::{
+            cache::Cache,
+            error::{Interrupted, PartialCompletionError},
+            rate_limit::{RateLimit, RateLimiter},
+        },
+        prelude::*,
+        test_util::{Flaky, Slow},
+    };
+
+    #[tokio::test]
+    async fn stacks_layers() {
+        let usage = Usage::default();
+        let llm = Flaky::default()
+            .layer(Redact::new(|text| text.replace("secret", "***")))
+            .layer(usage.clone())
+            .layer(Retry {
+                attempts: 2,
+                backoff: Duration::from_millis(1),
+            })
+            .layer(Cache::memory());
+        let prompt = PromptBuilder::default()
+            .user("One secret")
+            .complete()
+            .user("Two")
+            .complete()
+            .build()
+            .unwrap();
+
+        let completion = llm.complete_chat(&prompt).await.unwrap();
+        assert_eq!(completion.messages[1].content, "echo One ***");
+        assert_eq!(completion.last_assistant_response().unwrap(), "echo Two");
+        // Second turn failed once and was retried
+        assert_eq!(usage.prompts(), 3);
+
+        llm.complete_chat(&prompt).await.unwrap();
+        assert_eq!(usage.prompts(), 3);
+    }
+
+    #[tokio::test]
+    async fn shares_timeout() {
+        let prompt = PromptBuilder::default()
+            .user("One")
+            .complete()
+            .user("Two")
+            .complete()
+            .timeout(Duration::from_millis(150))
+            .build()
+            .unwrap();
+        let error = Slow
+            .layer(Cache::memory())
+            .complete_chat(&prompt)
+            .await
+            .unwrap_err();
+        let partial = error.downcast::<PartialCompletionError>().unwrap();
+        assert!(matches!(
+            partial.interrupted(),
+            Some(Interrupted::TimedOut(_))
+        ));
+        assert_eq!(partial.step, 3);
+        assert_eq!(partial.completion.messages.len(), 3);
+
+        // No time left for the backoff
+        let flaky = Flaky {
+            requests: AtomicUsize::new(1),
+        };
+        let retrying = flaky.layer(Retry {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+        });
+        let error = retrying.complete_chat(&prompt).await.unwrap_err();
+        let partial = error.downcast::<PartialCompletionError>().unwrap();
+        assert_eq!(
+            partial.interrupted(),
+            Some(Interrupted::TimedOut(Duration::from_millis(150)))
+        );
+        assert_eq!(partial.completion.messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_every_turn() {
+        let limiter = Arc::new(RateLimiter::default().with_model(
+            "slow",
+            RateLimit {
+                requests_per_minute: Some(2),
+                tokens_per_minute: None,
+            },
+        ));
+        let prompt = PromptBuilder::default()
+            .user("One")
+            .complete()
+            .user("Two")
+            .build()
+            .unwrap();
+        let completion = Slow
+            .layer(RateLimitLayer::new(limiter.clone()))
+            .complete_chat(&prompt)
+            .await
+            .unwrap();
+        assert_eq!(completion.messages.len(), 4);
+        // Both turns took from the budget of the provider model
+        let acquire = limiter.acquire("slow", 0);
+        assert!(tokio::time::timeout(Duration::from_millis(10), acquire)
+            .await
+            .is_err());
+    }
+}
@@ -1,14 +1,102 @@
 use async_trait::async_trait;
-use std::borrow::Borrow;
+use std::sync::Arc;
 
 use crate::{Completion, Prompt};
+use layer::Layer;
 
+pub mod cache;
 pub mod chat_gpt;
+pub mod error;
+pub mod layer;
+pub mod rate_limit;
+pub mod router;
+mod turns;
+
+/// Receives assistant response chunks as they are generated
+pub type TokenSink<'a> = &'a mut (dyn FnMut(&str) + Send);
+
+/// Object safe, a provider chosen at runtime is held as `Box<dyn LlmProvider>`
+/// or `Arc<dyn LlmProvider>` which are providers too
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion>;
+
+    /// Same as [`LlmProvider::complete_chat`] streaming assistant responses into the sink,
+    /// providers without streaming emit the last response at once
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        let completion = self.complete_chat(prompt).await?;
+        if let Ok(response) = completion.last_assistant_response() {
+            sink(&response);
+        }
+        Ok(completion)
+    }
+
+    /// Model completing prompts which do not set one, `None` when unknown or varying
+    fn default_model(&self) -> Option<String> {
+        None
+    }
+
+    /// Settings of the provider changing its replies besides the prompt, e.g. the default model,
+    /// part of the [`cache::Cache`] keys
+    fn cache_identity(&self) -> String {
+        String::new()
+    }
+
+    /// Wraps the provider, e.g. `ChatGpt::from_env().layer(Retry::default())`
+    fn layer<L: Layer<Self>>(self, layer: L) -> L::Provider
+    where
+        Self: Sized,
+    {
+        layer.layer(self)
+    }
+}
 
 #[async_trait]
-pub trait LlmProvider {
-    async fn complete_chat(
+impl<P: LlmProvider + ?Sized> LlmProvider for Box<P> {
+    fn default_model(&self) -> Option<String> {
+        (**self).default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        (**self).cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        (**self).complete_chat(prompt).await
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        (**self).complete_chat_streaming(prompt, sink).await
+    }
+}
+
+#[async_trait]
+impl<P: LlmProvider + ?Sized> LlmProvider for Arc<P> {
+    fn default_model(&self) -> Option<String> {
+        (**self).default_model()
+    }
+
+    fn cache_identity(&self) -> String {
+        (**self).cache_identity()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        (**self).complete_chat(prompt).await
+    }
+
+    async fn complete_chat_streaming(
         &self,
-        prompt: impl Borrow<Prompt> + std::marker::Send,
-    ) -> anyhow::Result<Completion>;
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        (**self).complete_chat_streaming(prompt, sink).await
+    }
 }
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Budget per minute, `None` is unlimited
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// Client-side limiter spreading requests over the per-minute budgets of every model,
+/// share it between providers with an `Arc`
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    default: RateLimit,
+    models: HashMap<String, RateLimit>,
+    budgets: Mutex<HashMap<String, Budget>>,
+}
+
+/// Token buckets which start full and refill continuously
+#[derive(Debug)]
+struct Budget {
+    requests: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Limit applied to every model without its own limit
+    pub fn new(default: RateLimit) -> Self {
+        Self {
+            default,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>, limit: RateLimit) -> Self {
+        self.models.insert(model.into(), limit);
+        self
+    }
+
+    /// Waits until the model budget allows a request using about `tokens` tokens
+    pub async fn acquire(&self, model: &str, tokens: usize) {
+        while let Err(wait) = self.try_acquire(model, tokens, Instant::now()) {
+            log::debug!("Rate limit of {model} is reached, waiting {wait:?}");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes from the budget or returns the time to wait for it to refill
+    fn try_acquire(&self, model: &str, tokens: usize, now: Instant) -> Result<(), Duration> {
+        let limit = self.models.get(model).copied().unwrap_or(self.default);
+        let mut budgets = self.budgets.lock().expect("Rate limiter lock is poisoned");
+        let budget = budgets.entry(model.to_string()).or_insert_with(|| Budget {
+            requests: limit.requests_per_minute.unwrap_or(0) as f64,
+            tokens: limit.tokens_per_minute.unwrap_or(0) as f64,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(budget.updated).as_secs_f64();
+        budget.updated = now;
+
+        let mut wait: f64 = 0.0;
+        if let Some(per_minute) = limit.requests_per_minute {
+            budget.requests = refill(budget.requests, per_minute, elapsed);
+            wait = wait.max(deficit_secs(budget.requests, 1.0, per_minute));
+        }
+        // Requests larger than the whole budget wait for the full bucket
+        let tokens = match limit.tokens_per_minute {
+            Some(per_minute) => (tokens as f64).min(per_minute as f64),
+            None => 0.0,
+        };
+        if let Some(per_minute) = limit.tokens_per_minute {
+            budget.tokens = refill(budget.tokens, per_minute, elapsed);
+            wait = wait.max(deficit_secs(budget.tokens, tokens, per_minute));
+        }
+        if wait > 0.0 {
+            return Err(Duration::from_secs_f64(wait));
+        }
+        budget.requests -= 1.0;
+        budget.tokens -= tokens;
+        Ok(())
+    }
+}
+
+fn refill(available: f64, per_minute: u32, elapsed_secs: f64) -> f64 {
+    (available + per_minute as f64 * elapsed_secs / 60.0).min(per_minute as f64)
+}
+
+fn deficit_secs(available: f64, needed: f64, per_minute: u32) -> f64 {
+    if available >= needed || per_minute == 0 {
+        return 0.0;
+    }
+    (needed - available) * 60.0 / per_minute as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimit, RateLimiter};
+
+    #[test]
+    fn spreads_requests() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_minute: Some(2),
+            tokens_per_minute: None,
+        })
+        .with_model(
+            "gpt-4o-mini",
+            RateLimit {
+                requests_per_minute: None,
+                tokens_per_minute: Some(600),
+            },
+        );
+        let start = Instant::now();
+        assert!(limiter.try_acquire("gpt-4o", 100, start).is_ok());
+        assert!(limiter.try_acquire("gpt-4o", 100, start).is_ok());
+        let wait = limiter.try_acquire("gpt-4o", 100, start).unwrap_err();
+        assert_eq!(wait.as_secs(), 30);
+        assert!(limiter
+            .try_acquire("gpt-4o", 100, start + Duration::from_secs(30))
+            .is_ok());
+
+        assert!(limiter.try_acquire("gpt-4o-mini", 500, start).is_ok());
+        let wait = limiter.try_acquire("gpt-4o-mini", 200, start).unwrap_err();
+        assert_eq!(wait.as_secs(), 10);
+        // Larger than the budget, waits for the full bucket instead of forever
+        assert!(limiter
+            .try_acquire("gpt-4o-mini", 5000, start + Duration::from_secs(50))
+            .is_ok());
+    }
+}
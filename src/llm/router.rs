@@ -0,0 +1,234 @@
+use async_trait::async_trait;
+
+use super::{
+    chat_gpt::count_tokens,
+    error::Interrupted,
+    turns::{complete_turn, Deadline, Reply, Turns},
+    LlmProvider, TokenSink,
+};
+use crate::{Completion, Prompt, PromptMessageRequest};
+
+struct Backend {
+    name: String,
+    provider: Box<dyn LlmProvider>,
+    /// Largest prompt in tokens the backend is used for
+    context_window: Option<usize>,
+}
+
+/// Provider completing every turn with the first backend which fits the prompt and succeeds,
+/// the backends are recorded in [`Completion::served_by`] which other providers leave empty.
+/// The layers sending a turn at a time such as [`super::cache::Cached`] keep it.
+///
+/// ```no_run
+/// # use promptpunch::{llm::{chat_gpt::ChatGptModel, router::Router}, prelude::*};
+/// let mini = ChatGpt::from_env().with_model(ChatGptModel::Mini4o);
+/// let window = mini.model.context_window();
+/// let router = Router::new()
+///     .with_limited_backend("gpt-4o-mini", mini, window)
+///     .with_backend("gpt-4o", ChatGpt::from_env().with_model(ChatGptModel::Latest4o))
+///     .with_backend("ollama", ChatGpt::from_env().with_base_url("http://localhost:11434/v1"));
+/// ```
+///
+/// Backends use their own models, the models set by the prompt are ignored.
+/// A streamed turn failing midway is streamed again by the next backend
+/// within the time left of [`Prompt::timeout`].
+#[derive(Default)]
+pub struct Router {
+    backends: Vec<Backend>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tried after the backends added before
+    pub fn with_backend(
+        mut self,
+        name: impl Into<String>,
+        provider: impl LlmProvider + 'static,
+    ) -> Self {
+        self.backends.push(Backend {
+            name: name.into(),
+            provider: Box::new(provider),
+            context_window: None,
+        });
+        self
+    }
+
+    /// Skipped for prompts longer than `context_window` tokens,
+    /// add the smaller models first to route by the prompt size
+    pub fn with_limited_backend(
+        mut self,
+        name: impl Into<String>,
+        provider: impl LlmProvider + 'static,
+        context_window: usize,
+    ) -> Self {
+        self.backends.push(Backend {
+            name: name.into(),
+            provider: Box::new(provider),
+            context_window: Some(context_window),
+        });
+        self
+    }
+
+    async fn run_prompt(
+        &self,
+        prompt: &Prompt,
+        mut sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<Completion> {
+        let mut prompt = prompt.clone();
+        prompt.model = None;
+        for request in &mut prompt.messages {
+            if let PromptMessageRequest::WaitCompletionWith { params } = request {
+                params.model = None;
+            }
+        }
+
+        let deadline = Deadline::start(&prompt);
+        let mut turns = Turns::new(&prompt);
+        while let Some((step, turn)) = turns.next_turn() {
+            match self
+                .complete_turn(turn, deadline, sink.as_deref_mut())
+                .await
+            {
+                Ok(reply) => turns.reply(reply),
+                Err(error) => return Err(turns.fail(step, error).into()),
+            }
+        }
+        Ok(turns.finish())
+    }
+
+    /// Reply of the first backend which succeeds served by its name
+    async fn complete_turn(
+        &self,
+        turn: &Prompt,
+        deadline: Deadline,
+        mut sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+    ) -> anyhow::Result<Reply> {
+        let tokens = turn
+            .messages
+            .iter()
+            .map(|request| match request {
+                PromptMessageRequest::Message { body } => count_tokens(&body.content),
+                _ => 0,
+            })
+            .sum::<usize>();
+        let mut failures = vec![];
+        for backend in &self.backends {
+            if backend.context_window.is_some_and(|window| tokens > window) {
+                continue;
+            }
+            let reply = complete_turn(&backend.provider, turn, deadline, sink.as_deref_mut());
+            match reply.await {
+                Ok(reply) => {
+                    return Ok(Reply {
+                        served_by: vec![backend.name.clone()],
+                        ..reply
+                    })
+                }
+                // Timeouts and cancellations are up to the caller
+                Err(error) if error.chain().any(|cause| cause.is::<Interrupted>()) => {
+                    return Err(error)
+                }
+                Err(error) => {
+                    log::warn!("Backend {} failed, falling back: {error:#}", backend.name);
+                    failures.push(format!("{}: {error:#}", backend.name));
+                }
+            }
+        }
+        if failures.is_empty() {
+            anyhow::bail!("No backend fits the prompt of {tokens} tokens");
+        }
+        anyhow::bail!("Every backend failed, {}", failures.join("; "))
+    }
+}
+
+#[async_trait]
+impl LlmProvider for Router {
+    fn cache_identity(&self) -> String {
+        self.backends
+            .iter()
+            .map(|backend| format!("{}={}", backend.name, backend.provider.cache_identity()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, None).await
+    }
+
+    async fn complete_chat_streaming(
+        &self,
+        prompt: &Prompt,
+        sink: TokenSink<'_>,
+    ) -> anyhow::Result<Completion> {
+        self.run_prompt(prompt, Some(sink)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Router;
+    use crate::{
+        llm::{cache::Cache, error::PartialCompletionError, layer::Retry},
+        prelude::*,
+        test_util::{FailsSecond, Named},
+    };
+
+    #[tokio::test]
+    async fn falls_back_and_routes_by_size() {
+        let router = Router::new()
+            .with_limited_backend("small", Named(Some("small")), 10)
+            .with_backend("down", Named(None))
+            .with_backend("large", Named(Some("large")));
+        let prompt = PromptBuilder::default()
+            .user("Hi")
+            .complete()
+            .user("Tell me a long story ".repeat(5))
+            .model("gpt-4o".to_string())
+            .build()
+            .unwrap();
+        let completion = router.complete_chat(&prompt).await.unwrap();
+        assert_eq!(completion.served_by, ["small", "large"]);
+        assert_eq!(completion.messages[1].content, "small");
+        assert_eq!(completion.last_assistant_response().unwrap(), "large");
+
+        let router = Router::new()
+            .with_limited_backend("small", Named(Some("small")), 10)
+            .with_backend("down", Named(None));
+        let error = router.complete_chat(&prompt).await.unwrap_err();
+        let partial = error.downcast::<PartialCompletionError>().unwrap();
+        assert_eq!(partial.completion.served_by, ["small"]);
+        assert_eq!(partial.step, 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_served_by_in_layers() {
+        let prompt = PromptBuilder::default()
+            .user("Hi")
+            .complete()
+            .user("Bye")
+            .build()
+            .unwrap();
+        let cached = Router::new()
+            .with_backend("named", Named(Some("named")))
+            .layer(Cache::memory());
+        let completion = cached.complete_chat(&prompt).await.unwrap();
+        assert_eq!(completion.served_by, ["named", "named"]);
+        let completion = cached.complete_chat(&prompt).await.unwrap();
+        assert_eq!(completion.served_by, ["named", "named"]);
+
+        let retrying = Router::new()
+            .with_backend("flaky", FailsSecond::default())
+            .layer(Retry {
+                attempts: 2,
+                backoff: Duration::from_millis(1),
+            });
+        let completion = retrying.complete_chat(&prompt).await.unwrap();
+        assert_eq!(completion.messages.len(), 4);
+        assert_eq!(completion.served_by, ["flaky", "flaky"]);
+    }
+}
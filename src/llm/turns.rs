@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use super::{
+    cache::into_completion,
+    error::{Interrupted, PartialCompletionError},
+    LlmProvider,
+};
+use crate::{Completion, Prompt, PromptMessage, PromptMessageRequest, Role};
+
+/// Requests of the prompt, a prompt without trailing completion point is completed at the end
+pub(super) fn with_final_completion(
+    prompt: &Prompt,
+) -> impl Iterator<Item = &PromptMessageRequest> {
+    let implicit_completion = (!matches!(
+        prompt.messages.last(),
+        Some(PromptMessageRequest::WaitCompletion)
+            | Some(PromptMessageRequest::WaitCompletionWith { .. })
+    ))
+    .then_some(&PromptMessageRequest::WaitCompletion);
+    prompt.messages.iter().chain(implicit_completion)
+}
+
+/// Splits a prompt into requests ending with a single completion point,
+/// for providers sending every turn separately
+pub(super) struct Turns {
+    /// Finished turns followed by the pending completion point
+    turn: Prompt,
+    requests: std::vec::IntoIter<(usize, PromptMessageRequest)>,
+    served_by: Vec<String>,
+}
+
+/// Reply to a turn with the backends which served it, see [`Completion::served_by`]
+pub(super) struct Reply {
+    pub(super) message: PromptMessage,
+    pub(super) served_by: Vec<String>,
+}
+
+impl Turns {
+    pub(super) fn new(prompt: &Prompt) -> Self {
+        let requests = with_final_completion(prompt)
+            .cloned()
+            .enumerate()
+            .collect::<Vec<_>>();
+        let mut turn = prompt.clone();
+        turn.messages.clear();
+        Self {
+            turn,
+            requests: requests.into_iter(),
+            served_by: vec![],
+        }
+    }
+
+    /// Next turn ending with its completion point and the step of the point in the prompt
+    pub(super) fn next_turn(&mut self) -> Option<(usize, &Prompt)> {
+        for (step, request) in self.requests.by_ref() {
+            let completion = !matches!(request, PromptMessageRequest::Message { .. });
+            self.turn.messages.push(request);
+            if completion {
+                return Some((step, &self.turn));
+            }
+        }
+        None
+    }
+
+    /// Replaces the pending completion point
+    pub(super) fn reply(&mut self, reply: Reply) {
+        self.turn.messages.pop();
+        self.turn.messages.push(PromptMessageRequest::Message {
+            body: reply.message,
+        });
+        self.served_by.extend(reply.served_by);
+    }
+
+    /// Error keeping the finished turns
+    pub(super) fn fail(mut self, step: usize, error: anyhow::Error) -> PartialCompletionError {
+        self.turn.messages.pop();
+        PartialCompletionError::new(self.finish(), step, error)
+    }
+
+    pub(super) fn finish(self) -> Completion {
+        let mut completion = into_completion(self.turn.messages);
+        completion.served_by = self.served_by;
+        completion
+    }
+}
+
+/// Reply of the provider to a turn of [`Turns`] within the time left
+pub(super) async fn complete_turn<P: LlmProvider + ?Sized>(
+    provider: &P,
+    turn: &Prompt,
+    deadline: Deadline,
+    sink: Option<&mut (dyn FnMut(&str) + Send + '_)>,
+) -> anyhow::Result<Reply> {
+    let turn = deadline.limit(turn)?;
+    let completion = match sink {
+        Some(sink) => provider.complete_chat_streaming(&turn, sink).await?,
+        None => provider.complete_chat(&turn).await?,
+    };
+    let message = PromptMessage {
+        role: Role::Assistant,
+        content: completion.last_assistant_response()?,
+    };
+    Ok(Reply {
+        message,
+        served_by: completion.served_by,
+    })
+}
+
+/// [`Prompt::timeout`] of a whole completion shared by the requests sent for it
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Deadline {
+    limit: Option<(Duration, Instant)>,
+}
+
+impl Deadline {
+    pub(super) fn start(prompt: &Prompt) -> Self {
+        Self {
+            limit: prompt
+                .timeout
+                .map(|timeout| (timeout, Instant::now() + timeout)),
+        }
+    }
+
+    /// Time left, `None` without timeout
+    pub(super) fn left(&self) -> Option<Duration> {
+        self.limit
+            .map(|(_, at)| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// The request with the time left as its timeout,
+    /// fails with [`Interrupted::TimedOut`] once the time ran out
+    pub(super) fn limit<'a>(&self, request: &'a Prompt) -> anyhow::Result<Cow<'a, Prompt>> {
+        let (Some((timeout, _)), Some(left)) = (self.limit, self.left()) else {
+            return Ok(Cow::Borrowed(request));
+        };
+        if left.is_zero() {
+            return Err(Interrupted::TimedOut(timeout).into());
+        }
+        let mut request = request.clone();
+        request.timeout = Some(left);
+        Ok(Cow::Owned(request))
+    }
+
+    /// Marks the error of the last request as timed out keeping its finished turns,
+    /// e.g. when no time is left to retry
+    pub(super) fn time_out(&self, error: anyhow::Error) -> anyhow::Error {
+        let Some((timeout, _)) = self.limit else {
+            return error;
+        };
+        let timed_out = |error: &anyhow::Error| {
+            anyhow::Error::from(Interrupted::TimedOut(timeout))
+                .context(format!("No time left to retry {error:#}"))
+        };
+        match error.downcast::<PartialCompletionError>() {
+            Ok(partial) => {
+                let error = timed_out(&partial.error);
+                PartialCompletionError::new(partial.completion, partial.step, error).into()
+            }
+            Err(error) => timed_out(&error),
+        }
+    }
+}
@@ -1,21 +1,37 @@
 #[tokio::main]
 async fn main() {
-    if cfg!(feature = "cli") {
-        cli::run().await.unwrap();
-    }
+    #[cfg(feature = "cli")]
+    cli::run().await.unwrap();
 }
 
+#[cfg(all(test, feature = "cli"))]
+#[path = "test_util.rs"]
+#[allow(dead_code)]
+mod test_util;
+
 #[cfg(feature = "cli")]
 mod cli {
-    use std::path::PathBuf;
+    use std::{
+        io::{BufRead, Write},
+        path::{Path, PathBuf},
+    };
 
-    use clap::{command, Parser, Subcommand, ValueEnum};
+    use anyhow::Context;
+    use clap::{Parser, Subcommand, ValueEnum};
     use promptpunch::{
-        llm::LlmProvider,
-        prelude::ChatGpt,
-        prompt::{read_markdown_prompt_from_file, InjectableData},
-        PromptBuilder,
+        batch::{read_rows, Batch},
+        config::{Config, Profile, ProviderKind},
+        conversation::Conversation,
+        llm::{chat_gpt::ChatGptModel, LlmProvider},
+        prelude::{Prompt, PromptBuilder, Role},
+        prompt::{
+            formats::{read_prompt_from_file, PromptTemplate},
+            lint::{lint_markdown_prompt, LintIssue, Severity},
+            IncludeResolver, InjectableData,
+        },
+        Completion,
     };
+    use serde::Serialize;
 
     #[derive(Parser, Debug)]
     struct Args {
@@ -23,23 +39,338 @@ mod cli {
         cmd: Command,
     }
 
+    /// Provider settings overriding the selected profile of the config file
+    #[derive(clap::Args, Debug)]
+    struct ProviderArgs {
+        /// Profile of the config file, by default its `default_profile`
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Config file instead of `~/.config/promptpunch/config.toml`
+        #[arg(long)]
+        config: Option<PathBuf>,
+
+        /// API flavour, only `openai` for now
+        #[arg(long)]
+        provider: Option<ProviderKind>,
+
+        /// OpenAI compatible API root, e.g. `http://localhost:11434/v1`
+        #[arg(long)]
+        base_url: Option<String>,
+
+        /// Model name, e.g. `gpt-4o-mini`, overrides the prompt model
+        #[arg(short, long)]
+        model: Option<ChatGptModel>,
+
+        /// Overrides the prompt temperature
+        #[arg(short, long)]
+        temperature: Option<f32>,
+
+        #[arg(long)]
+        max_tokens: Option<u32>,
+
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Client-side limit, requests wait instead of failing with 429
+        #[arg(long)]
+        requests_per_minute: Option<u32>,
+
+        /// Client-side limit counting the prompt and `--max-tokens`
+        #[arg(long)]
+        tokens_per_minute: Option<u32>,
+
+        /// Seconds the whole completion may take
+        #[arg(long)]
+        timeout: Option<u64>,
+    }
+
+    /// Settings of a command, prompt files keep their model and temperature
+    /// unless the flags set them
+    struct Settings {
+        /// Selected profile of the config file merged with the flags, builds the provider
+        profile: Profile,
+        /// Flags written over the prompt and the timeout of the profile
+        overrides: Profile,
+    }
+
+    impl ProviderArgs {
+        fn into_settings(self) -> anyhow::Result<Settings> {
+            let config = match &self.config {
+                Some(path) => Config::load(path)?,
+                None => Config::load_default()?,
+            };
+            let overrides = Profile {
+                provider: self.provider,
+                base_url: self.base_url,
+                model: self.model.map(|model| model.to_string()),
+                temperature: self.temperature,
+                max_tokens: self.max_tokens,
+                seed: self.seed,
+                requests_per_minute: self.requests_per_minute,
+                tokens_per_minute: self.tokens_per_minute,
+                timeout: self.timeout,
+            };
+            let profile = config
+                .profile(self.profile.as_deref())?
+                .merge(overrides.clone());
+            let overrides = Profile {
+                model: overrides.model,
+                temperature: overrides.temperature,
+                timeout: profile.timeout,
+                ..Default::default()
+            };
+            Ok(Settings { profile, overrides })
+        }
+    }
+
     #[derive(Subcommand, Debug)]
     enum Command {
+        /// Completes the prompt and prints the reply
         Complete {
+            /// Prompt file, format is detected from the extension: `.md`, `.yaml`, `.toml`, `.json`
             #[arg(short, long)]
             prompt: PathBuf,
 
+            /// Placeholder value as `name=value`, `name=@path` reads a file,
+            /// `name=@-` reads stdin, `name=@*.md` concatenates files, `name=@https://..` fetches URL
             #[arg(short, long, value_parser = parse_key_value)]
             argument: Vec<(String, String)>,
 
-            #[arg(short, long)]
+            #[arg(short, long, default_value = "last")]
             output: PromptOutput,
+
+            /// Writes the output to the file instead of stdout
+            #[arg(long)]
+            out: Option<PathBuf>,
+
+            /// Directory to search for included prompts, may be repeated
+            #[arg(short = 'I', long)]
+            include_dir: Vec<PathBuf>,
+
+            #[command(flatten)]
+            provider: ProviderArgs,
+        },
+        /// Interactive chat, type `/help` for the commands
+        Chat {
+            /// Prompt to start the chat with, completed right away when it ends with a user message
+            #[arg(short, long)]
+            prompt: Option<PathBuf>,
+
+            /// Placeholder value as `name=value`, same sources as for `complete`
+            #[arg(short, long, value_parser = parse_key_value)]
+            argument: Vec<(String, String)>,
+
+            /// Directory to search for included prompts, may be repeated
+            #[arg(short = 'I', long)]
+            include_dir: Vec<PathBuf>,
+
+            #[command(flatten)]
+            provider: ProviderArgs,
+        },
+        /// Completes the prompt for every row of a dataset, rerun with the same output to resume
+        Batch {
+            #[arg(short, long)]
+            prompt: PathBuf,
+
+            /// `.jsonl` with an object per line or `.csv` with a header,
+            /// keys and columns are the placeholder names without braces
+            #[arg(short, long)]
+            input: PathBuf,
+
+            /// JSONL with the response, token usage and error per row
+            #[arg(short, long)]
+            output: PathBuf,
+
+            /// Number of completions requested at the same time
+            #[arg(short, long, default_value_t = 4)]
+            concurrency: usize,
+
+            /// Directory to search for included prompts, may be repeated
+            #[arg(short = 'I', long)]
+            include_dir: Vec<PathBuf>,
+
+            #[command(flatten)]
+            provider: ProviderArgs,
+        },
+        /// Checks markdown prompts for common mistakes, exits with 1 on errors
+        Lint {
+            #[arg(required = true)]
+            paths: Vec<PathBuf>,
+
+            #[arg(short, long, default_value = "text")]
+            format: LintFormat,
+
+            /// Model checked when the prompt front matter sets none
+            #[arg(short, long, default_value_t)]
+            model: ChatGptModel,
+
+            /// Directory to search for included prompts, may be repeated
+            #[arg(short = 'I', long)]
+            include_dir: Vec<PathBuf>,
         },
     }
 
     #[derive(Clone, Debug, ValueEnum)]
     enum PromptOutput {
+        /// Last assistant message
         Last,
+        /// Every assistant message separated by an empty line
+        All,
+        /// Whole conversation as markdown, reusable as a prompt
+        Transcript,
+        /// Completion with the messages, token usage and parameters
+        Json,
+        /// JSON object per message
+        Jsonl,
+    }
+
+    #[derive(Serialize)]
+    struct CompletionOutput<'a> {
+        /// `null` when the provider does not tell its default model
+        model: Option<String>,
+        temperature: f32,
+        #[serde(flatten)]
+        completion: &'a Completion,
+    }
+
+    #[derive(Clone, Debug, ValueEnum)]
+    enum LintFormat {
+        /// `path:line: severity[code]: message`
+        Text,
+        /// JSON object per line
+        Json,
+    }
+
+    #[derive(Serialize)]
+    struct FileLintIssue<'a> {
+        path: &'a Path,
+        #[serde(flatten)]
+        issue: &'a LintIssue,
+    }
+
+    fn include_resolver(include_dir: Vec<PathBuf>) -> IncludeResolver {
+        include_dir.into_iter().fold(
+            IncludeResolver::from_env(),
+            IncludeResolver::with_search_path,
+        )
+    }
+
+    const CHAT_HELP: &str = "\
+/save <path>          save the transcript as markdown
+/model <name>         switch the model, e.g. gpt-4o-mini
+/temperature <value>  switch the temperature
+/tokens               show the tokens used so far
+/undo                 remove the last user message with the reply
+/retry                regenerate the last reply
+/exit                 quit, same as Ctrl-D";
+
+    /// Seed prompt of the chat, empty without a prompt file
+    async fn chat_prompt(
+        prompt: Option<PathBuf>,
+        argument: Vec<(String, String)>,
+        include_dir: Vec<PathBuf>,
+    ) -> anyhow::Result<Prompt> {
+        let Some(prompt) = prompt else {
+            return Ok(PromptBuilder::default().build()?);
+        };
+        let mut data = vec![];
+        for (placeholder, value) in argument {
+            data.push(InjectableData::from_source(placeholder, &value).await?);
+        }
+        let resolver = include_resolver(include_dir);
+        read_prompt_from_file(prompt, data.as_slice(), &resolver)
+    }
+
+    async fn chat(prompt: Prompt, llm: Box<dyn LlmProvider>) -> anyhow::Result<()> {
+        let mut conversation = Conversation::new(llm, prompt);
+        let mut print = |token: &str| {
+            print!("{token}");
+            std::io::stdout().flush().ok();
+        };
+
+        if matches!(
+            conversation.messages().last().map(|message| message.role),
+            Some(Role::User)
+        ) {
+            conversation.complete_streaming(&mut print).await?;
+            println!();
+        }
+
+        let mut lines = std::io::stdin().lock().lines();
+        loop {
+            print!("> ");
+            std::io::stdout().flush()?;
+            let Some(line) = lines.next().transpose()? else {
+                println!();
+                break;
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (command, value) = match line.split_once(' ') {
+                Some((command, value)) => (command, value.trim()),
+                None => (line, ""),
+            };
+            let result = match command {
+                "/exit" | "/quit" => break,
+                "/help" => {
+                    println!("{CHAT_HELP}");
+                    Ok(())
+                }
+                "/save" if !value.is_empty() => {
+                    std::fs::write(value, conversation.to_completion().to_markdown())
+                        .map(|_| println!("Saved to {value}"))
+                        .map_err(Into::into)
+                }
+                "/save" => {
+                    println!("Usage: /save <path>");
+                    Ok(())
+                }
+                "/model" if !value.is_empty() => {
+                    conversation.prompt_mut().model = Some(value.to_string());
+                    Ok(())
+                }
+                "/temperature" => match value.parse::<f32>() {
+                    Ok(temperature) if (0.0..=2.0).contains(&temperature) => {
+                        conversation.prompt_mut().temperature = temperature;
+                        Ok(())
+                    }
+                    _ => Err(anyhow::anyhow!("Temperature must be between 0 and 2")),
+                },
+                "/tokens" => {
+                    println!(
+                        "user: {}, assistant: {}",
+                        conversation.user_tokens, conversation.assistant_tokens
+                    );
+                    Ok(())
+                }
+                "/undo" => {
+                    match conversation.undo() {
+                        Some(message) => println!("Removed: {message}"),
+                        None => println!("Nothing to undo"),
+                    }
+                    Ok(())
+                }
+                "/retry" => conversation
+                    .retry_streaming(&mut print)
+                    .await
+                    .map(|_| println!()),
+                command if command.starts_with('/') => {
+                    Err(anyhow::anyhow!("Unknown command {command}, see /help"))
+                }
+                _ => conversation
+                    .send_streaming(line, &mut print)
+                    .await
+                    .map(|_| println!()),
+            };
+            if let Err(err) = result {
+                eprintln!("Error: {err}");
+            }
+        }
+        Ok(())
     }
 
     fn parse_key_value(input: &str) -> Result<(String, String), String> {
@@ -52,31 +383,187 @@ mod cli {
 
     pub async fn run() -> anyhow::Result<()> {
         let args = Args::parse();
-        let llm = ChatGpt::from_env();
         match args.cmd {
             Command::Complete {
                 prompt,
                 argument,
                 output,
+                out,
+                include_dir,
+                provider,
+            } => {
+                let Settings { profile, overrides } = provider.into_settings()?;
+                let mut data = vec![];
+                for (placeholder, value) in argument {
+                    data.push(InjectableData::from_source(placeholder, &value).await?);
+                }
+                let resolver = include_resolver(include_dir);
+                let mut prompt = read_prompt_from_file(prompt, data.as_slice(), &resolver)?;
+                overrides.apply_to_prompt(&mut prompt)?;
+                let llm = profile.provider()?;
+                let model = prompt.model.clone().or_else(|| llm.default_model());
+                let temperature = prompt.temperature;
+                let completion = llm.complete_chat(&prompt).await?;
+
+                let text = match output {
+                    PromptOutput::Last => completion.last_assistant_response()?,
+                    PromptOutput::All => completion
+                        .messages
+                        .iter()
+                        .filter(|message| message.role == Role::Assistant)
+                        .map(|message| message.content.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n\n"),
+                    PromptOutput::Transcript => completion.to_markdown(),
+                    PromptOutput::Json => serde_json::to_string_pretty(&CompletionOutput {
+                        model,
+                        temperature,
+                        completion: &completion,
+                    })?,
+                    PromptOutput::Jsonl => completion
+                        .messages
+                        .iter()
+                        .map(serde_json::to_string)
+                        .collect::<Result<Vec<_>, _>>()?
+                        .join("\n"),
+                };
+                match out {
+                    Some(out) => std::fs::write(&out, text + "\n")
+                        .with_context(|| format!("Failed to write output to {}", out.display()))?,
+                    None => println!("{text}"),
+                }
+            }
+            Command::Chat {
+                prompt,
+                argument,
+                include_dir,
+                provider,
+            } => {
+                let Settings { profile, overrides } = provider.into_settings()?;
+                // A chat without prompt file takes the temperature of the profile too
+                let settings = match prompt {
+                    Some(_) => overrides,
+                    None => profile.clone(),
+                };
+                let mut prompt = chat_prompt(prompt, argument, include_dir).await?;
+                settings.apply_to_prompt(&mut prompt)?;
+                chat(prompt, profile.provider()?).await?;
+            }
+            Command::Batch {
+                prompt,
+                input,
+                output,
+                concurrency,
+                include_dir,
+                provider,
             } => {
-                let data = argument
-                    .into_iter()
-                    .map(|(placeholder, value)| InjectableData::new(placeholder, value))
-                    .collect::<Vec<_>>();
-                let requests = read_markdown_prompt_from_file(prompt, data.as_slice())?;
-                let prompt = PromptBuilder::default()
-                    .messages(requests)
-                    .temperature(0.5)
-                    .build()?;
-                let completion = llm.complete_chat(prompt).await?;
-
-                match output {
-                    PromptOutput::Last => {
-                        println!("{}", completion.last_assistant_response()?);
+                let Settings { profile, overrides } = provider.into_settings()?;
+                let template = PromptTemplate::read(prompt, &include_resolver(include_dir))?;
+                let rows = read_rows(input)?;
+                let summary = Batch::new(profile.provider()?, template)
+                    .with_concurrency(concurrency)
+                    .with_prompt_override(move |prompt| overrides.apply_to_prompt(prompt))
+                    .run(&rows, &output)
+                    .await?;
+                eprintln!(
+                    "Completed {}, failed {}, skipped {} of {} rows",
+                    summary.completed,
+                    summary.failed,
+                    summary.skipped,
+                    rows.len()
+                );
+            }
+            Command::Lint {
+                paths,
+                format,
+                model,
+                include_dir,
+            } => {
+                let resolver = include_resolver(include_dir);
+                let mut failed = false;
+                for path in &paths {
+                    let markdown = resolver.read(path)?;
+                    for issue in lint_markdown_prompt(&markdown, &model) {
+                        failed |= issue.severity == Severity::Error;
+                        match format {
+                            LintFormat::Text => match issue.line {
+                                Some(_) => println!("{}:{}", path.display(), issue),
+                                None => println!("{}: {}", path.display(), issue),
+                            },
+                            LintFormat::Json => println!(
+                                "{}",
+                                serde_json::to_string(&FileLintIssue {
+                                    path,
+                                    issue: &issue
+                                })?
+                            ),
+                        }
                     }
                 }
+                if failed {
+                    std::process::exit(1);
+                }
             }
         }
         Ok(())
     }
+
+    #[cfg(test)]
+    mod tests {
+        use clap::Parser;
+        use promptpunch::{conversation::Conversation, prelude::*};
+
+        use super::{chat_prompt, Args, Command, Settings};
+        use crate::test_util::{Echo, TempDir};
+
+        #[test]
+        fn keeps_prompt_settings_over_profile() {
+            let dir = TempDir::new("config");
+            let config = dir.join("config.toml");
+            std::fs::write(
+                &config,
+                "default_profile = \"fast\"\n[profiles.fast]\nmodel = \"gpt-4o-mini\"\ntemperature = 0.1\n",
+            )
+            .unwrap();
+            let settings = |flags: &[&str]| {
+                let args = ["promptpunch", "complete", "-p", "prompt.md", "--config"];
+                let args = args.into_iter().chain([config.to_str().unwrap()]);
+                let Command::Complete { provider, .. } =
+                    Args::try_parse_from(args.chain(flags.iter().copied()))
+                        .unwrap()
+                        .cmd
+                else {
+                    unreachable!()
+                };
+                provider.into_settings().unwrap()
+            };
+            let prompt = PromptBuilder::default()
+                .user("Hi")
+                .model("gpt-4o".to_string())
+                .temperature(0.9)
+                .build()
+                .unwrap();
+
+            let Settings { profile, overrides } = settings(&[]);
+            assert_eq!(profile.model.as_deref(), Some("gpt-4o-mini"));
+            let mut kept = prompt.clone();
+            overrides.apply_to_prompt(&mut kept).unwrap();
+            assert_eq!(kept.model.as_deref(), Some("gpt-4o"));
+            assert_eq!(kept.temperature, 0.9);
+
+            let Settings { overrides, .. } = settings(&["--model", "gpt-4o-mini", "-t", "0.5"]);
+            let mut overridden = prompt;
+            overrides.apply_to_prompt(&mut overridden).unwrap();
+            assert_eq!(overridden.model.as_deref(), Some("gpt-4o-mini"));
+            assert_eq!(overridden.temperature, 0.5);
+        }
+
+        #[tokio::test]
+        async fn starts_chat_without_prompt() {
+            let prompt = chat_prompt(None, vec![], vec![]).await.unwrap();
+            assert!(prompt.messages.is_empty());
+            let mut conversation = Conversation::new(Echo::default(), prompt);
+            assert_eq!(conversation.send("Hi").await.unwrap(), "echo Hi");
+        }
+    }
 }
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use super::{
+    front_matter_prompt, inject, parse_markdown_prompt, resolve_inputs, validate_placeholders,
+    FrontMatter, IncludeResolver, InjectableData, InputSpec,
+};
+use crate::{OutputFormat, Prompt, PromptMessage, PromptMessageRequest, Role};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptFormat {
+    Markdown,
+    Yaml,
+    Toml,
+    /// OpenAI `messages` array or chat completion request body
+    OpenAiJson,
+}
+
+impl PromptFormat {
+    /// Detects format from the file extension, falls back to markdown
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        Self::detect(path).unwrap_or(PromptFormat::Markdown)
+    }
+
+    /// Detects format from the file extension, `None` for unknown extensions
+    pub fn detect(path: impl AsRef<Path>) -> Option<Self> {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())?
+            .to_lowercase();
+        match extension.as_str() {
+            "md" | "markdown" => Some(PromptFormat::Markdown),
+            "yaml" | "yml" => Some(PromptFormat::Yaml),
+            "toml" => Some(PromptFormat::Toml),
+            "json" => Some(PromptFormat::OpenAiJson),
+            _ => None,
+        }
+    }
+
+    pub fn parse(&self, text: &str, injectable_data: &[InjectableData]) -> anyhow::Result<Prompt> {
+        match self {
+            PromptFormat::Markdown => parse_markdown_prompt(text, injectable_data),
+            PromptFormat::Yaml => parse_yaml_prompt(text, injectable_data),
+            PromptFormat::Toml => parse_toml_prompt(text, injectable_data),
+            PromptFormat::OpenAiJson => parse_openai_messages(text, injectable_data),
+        }
+    }
+}
+
+/// Reads prompt in the format detected from the file extension,
+/// markdown includes are resolved with the given resolver
+pub fn read_prompt_from_file(
+    path: impl AsRef<Path>,
+    injectable_data: &[InjectableData],
+    resolver: &IncludeResolver,
+) -> anyhow::Result<Prompt> {
+    PromptTemplate::read(path, resolver)?.render(injectable_data)
+}
+
+/// Prompt text read once and rendered with different data
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    pub format: PromptFormat,
+    /// Text with includes expanded
+    pub text: String,
+}
+
+impl PromptTemplate {
+    pub fn new(format: PromptFormat, text: impl Into<String>) -> Self {
+        Self {
+            format,
+            text: text.into(),
+        }
+    }
+
+    /// Reads prompt in the format detected from the file extension,
+    /// markdown includes are resolved with the given resolver
+    pub fn read(path: impl AsRef<Path>, resolver: &IncludeResolver) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let format = PromptFormat::from_path(path);
+        let text = match format {
+            PromptFormat::Markdown => resolver.read(path)?,
+            _ => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read prompt from {}", path.display()))?,
+        };
+        Ok(Self { format, text })
+    }
+
+    pub fn render(&self, injectable_data: &[InjectableData]) -> anyhow::Result<Prompt> {
+        self.format.parse(&self.text, injectable_data)
+    }
+}
+
+/// Prompt written as a YAML or TOML document.
+/// Assistant message without content waits for the completion.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PromptDocument {
+    name: Option<String>,
+    version: Option<String>,
+    model: Option<String>,
+    temperature: Option<f32>,
+    #[serde(default)]
+    inputs: BTreeMap<String, InputSpec>,
+    output: Option<OutputFormat>,
+    messages: Vec<DocumentMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DocumentMessage {
+    role: Role,
+    content: Option<String>,
+}
+
+pub fn parse_yaml_prompt(yaml: &str, injectable_data: &[InjectableData]) -> anyhow::Result<Prompt> {
+    let document =
+        serde_yaml::from_str::<PromptDocument>(yaml).context("Failed to parse YAML prompt")?;
+    document.into_prompt(injectable_data)
+}
+
+pub fn parse_toml_prompt(toml: &str, injectable_data: &[InjectableData]) -> anyhow::Result<Prompt> {
+    let document = toml::from_str::<PromptDocument>(toml).context("Failed to parse TOML prompt")?;
+    document.into_prompt(injectable_data)
+}
+
+impl PromptDocument {
+    fn into_prompt(self, injectable_data: &[InjectableData]) -> anyhow::Result<Prompt> {
+        let front_matter = FrontMatter {
+            name: self.name,
+            version: self.version,
+            model: self.model,
+            temperature: self.temperature,
+            inputs: self.inputs,
+            output: self.output,
+        };
+        let messages = self
+            .messages
+            .into_iter()
+            .map(|message| match message.content {
+                None if message.role == Role::Assistant => PromptMessageRequest::WaitCompletion,
+                content => PromptMessageRequest::Message {
+                    body: PromptMessage {
+                        role: message.role,
+                        content: content.unwrap_or_default(),
+                    },
+                },
+            })
+            .collect::<Vec<_>>();
+        let lines = messages.iter().flat_map(|request| match request {
+            PromptMessageRequest::Message { body } => body.content.lines().collect(),
+            _ => vec![],
+        });
+        let injectable_data = resolve_inputs(&front_matter, lines, injectable_data)?;
+        front_matter_prompt(front_matter, render_messages(messages, &injectable_data)?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAiDocument {
+    Messages(Vec<PromptMessage>),
+    Request {
+        model: Option<String>,
+        temperature: Option<f32>,
+        messages: Vec<PromptMessage>,
+    },
+}
+
+/// Parses OpenAI `messages` JSON array, or a chat completion request body with one
+pub fn parse_openai_messages(
+    json: &str,
+    injectable_data: &[InjectableData],
+) -> anyhow::Result<Prompt> {
+    let document = serde_json::from_str::<OpenAiDocument>(json)
+        .context("Failed to parse OpenAI messages JSON")?;
+    let (front_matter, messages) = match document {
+        OpenAiDocument::Messages(messages) => (FrontMatter::default(), messages),
+        OpenAiDocument::Request {
+            model,
+            temperature,
+            messages,
+        } => (
+            FrontMatter {
+                model,
+                temperature,
+                ..Default::default()
+            },
+            messages,
+        ),
+    };
+    let messages = messages
+        .into_iter()
+        .map(|body| PromptMessageRequest::Message { body })
+        .collect();
+    front_matter_prompt(front_matter, render_messages(messages, injectable_data)?)
+}
+
+fn render_messages(
+    messages: Vec<PromptMessageRequest>,
+    injectable_data: &[InjectableData],
+) -> anyhow::Result<Vec<PromptMessageRequest>> {
+    let contents = messages.iter().filter_map(|request| match request {
+        PromptMessageRequest::Message { body } => Some(body.content.as_str()),
+        PromptMessageRequest::WaitCompletion | PromptMessageRequest::WaitCompletionWith { .. } => {
+            None
+        }
+    });
+    validate_placeholders(contents.flat_map(str::lines), injectable_data)?;
+
+    Ok(messages
+        .into_iter()
+        .map(|request| match request {
+            PromptMessageRequest::Message { body } => PromptMessageRequest::Message {
+                body: PromptMessage {
+                    role: body.role,
+                    content: inject(&body.content, injectable_data),
+                },
+            },
+            completion => completion,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    use super::{parse_openai_messages, parse_toml_prompt, parse_yaml_prompt, PromptFormat};
+
+    #[test]
+    fn parses_documents() {
+        let yaml = r#"
+model: gpt-4o-mini
+temperature: 0.5
+messages:
+  - role: system
+    content: Act like a {persona}
+  - role: user
+    content: How r you?
+  - role: assistant
+  - role: user
+    content: Repeat ur words
+"#;
+        let toml = r#"
+model = "gpt-4o-mini"
+temperature = 0.5
+
+[[messages]]
+role = "system"
+content = "Act like a {persona}"
+
+[[messages]]
+role = "user"
+content = "How r you?"
+
+[[messages]]
+role = "assistant"
+
+[[messages]]
+role = "user"
+content = "Repeat ur words"
+"#;
+        let data = [InjectableData::new("{persona}", "Gandalf")];
+        let expected = vec![
+            message::system!("Act like a Gandalf"),
+            message::user!("How r you?"),
+            message::complete!(),
+            message::user!("Repeat ur words"),
+        ];
+        for prompt in [
+            parse_yaml_prompt(yaml, &data).unwrap(),
+            parse_toml_prompt(toml, &data).unwrap(),
+        ] {
+            assert_eq!(prompt.messages, expected);
+            assert_eq!(prompt.model.as_deref(), Some("gpt-4o-mini"));
+            assert_eq!(prompt.temperature, 0.5);
+        }
+        assert!(parse_yaml_prompt(yaml, &[]).is_err());
+    }
+
+    #[test]
+    fn parses_openai_messages() {
+        let json = r#"[
+            {"role": "system", "content": "Act like a Gandalf"},
+            {"role": "user", "content": "How r you?"}
+        ]"#;
+        let prompt = parse_openai_messages(json, &[]).unwrap();
+        assert_eq!(
+            prompt.messages,
+            vec![
+                message::system!("Act like a Gandalf"),
+                message::user!("How r you?")
+            ]
+        );
+
+        let json = r#"{"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}]}"#;
+        let prompt = parse_openai_messages(json, &[]).unwrap();
+        assert_eq!(prompt.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(prompt.messages, vec![message::user!("Hi")]);
+
+        assert_eq!(PromptFormat::from_path("a/b.yml"), PromptFormat::Yaml);
+        assert_eq!(PromptFormat::from_path("a/b.md"), PromptFormat::Markdown);
+    }
+}
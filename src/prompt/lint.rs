@@ -0,0 +1,297 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use super::{find_placeholders, parse_include, parse_role_header, split_front_matter};
+use crate::{
+    llm::chat_gpt::{count_tokens, ChatGptModel},
+    Role,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LintIssue {
+    pub severity: Severity,
+    /// Stable identifier of the check, e.g. `missing-system`
+    pub code: &'static str,
+    pub message: String,
+    /// 1-based line number in the linted markdown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+impl LintIssue {
+    fn new(severity: Severity, code: &'static str, message: impl Display) -> Self {
+        Self {
+            severity,
+            code,
+            message: message.to_string(),
+            line: None,
+        }
+    }
+
+    fn at(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+}
+
+impl Display for LintIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        if let Some(line) = self.line {
+            write!(f, "{line}: ")?;
+        }
+        write!(f, "{severity}[{}]: {}", self.code, self.message)
+    }
+}
+
+struct Section {
+    role: Role,
+    line: usize,
+    content: String,
+}
+
+/// Checks markdown prompt for common mistakes against the front matter model,
+/// `model` is used when the front matter sets none.
+/// Includes are not followed, expand them with [`super::IncludeResolver`] beforehand.
+pub fn lint_markdown_prompt(markdown: &str, model: &ChatGptModel) -> Vec<LintIssue> {
+    let (front_matter, body) = match split_front_matter(markdown) {
+        Ok(parts) => parts,
+        Err(err) => {
+            return vec![LintIssue::new(Severity::Error, "front-matter", format!("{err:#}")).at(1)]
+        }
+    };
+    let body_offset = markdown[..markdown.len() - body.len()].lines().count();
+    let mut issues = vec![];
+
+    let mut sections: Vec<Section> = vec![];
+    for (idx, line) in body.lines().enumerate() {
+        let line_number = body_offset + idx + 1;
+        if let Some(role) = parse_role_header(line) {
+            sections.push(Section {
+                role,
+                line: line_number,
+                content: String::new(),
+            });
+        } else if parse_include(line).is_some() {
+            issues.push(
+                LintIssue::new(
+                    Severity::Warning,
+                    "unexpanded-include",
+                    "Include is not expanded, placeholders and tokens of the included prompt are not checked",
+                )
+                .at(line_number),
+            );
+        } else if let Some(section) = sections.last_mut() {
+            section.content += line;
+            section.content.push('\n');
+        } else if !line.trim().is_empty() {
+            issues.push(
+                LintIssue::new(
+                    Severity::Error,
+                    "content-before-header",
+                    "Content precedes any role header and can't be parsed",
+                )
+                .at(line_number),
+            );
+        }
+    }
+
+    if sections.is_empty() {
+        issues.push(LintIssue::new(
+            Severity::Error,
+            "empty-prompt",
+            "Prompt has no role sections",
+        ));
+        return issues;
+    }
+
+    let model = match front_matter
+        .model
+        .as_deref()
+        .map(str::parse::<ChatGptModel>)
+    {
+        Some(Ok(model)) => model,
+        _ => model.clone(),
+    };
+    let model_name = model.to_string();
+    let is_o1 = model_name.starts_with("o1");
+    let system = sections.iter().find(|section| section.role == Role::System);
+    match system {
+        Some(section) if is_o1 => issues.push(
+            LintIssue::new(
+                Severity::Error,
+                "o1-system",
+                format!("Model {model_name} does not support system messages"),
+            )
+            .at(section.line),
+        ),
+        None if !is_o1 => issues.push(LintIssue::new(
+            Severity::Warning,
+            "missing-system",
+            "Prompt has no system section",
+        )),
+        _ => {}
+    }
+
+    for pair in sections.windows(2) {
+        let (previous, current) = (&pair[0], &pair[1]);
+        if previous.role == current.role {
+            issues.push(
+                LintIssue::new(
+                    Severity::Warning,
+                    "consecutive-role",
+                    format!(
+                        "{:?} section follows another {:?} section from line {}",
+                        current.role, previous.role, previous.line
+                    ),
+                )
+                .at(current.line),
+            );
+        }
+    }
+
+    for section in &sections {
+        if section.role != Role::Assistant && section.content.trim().is_empty() {
+            issues.push(
+                LintIssue::new(
+                    Severity::Warning,
+                    "empty-section",
+                    format!("{:?} section is empty", section.role),
+                )
+                .at(section.line),
+            );
+        }
+    }
+
+    let last = sections.last().expect("Sections are not empty");
+    if last.role != Role::Assistant || !last.content.trim().is_empty() {
+        issues.push(
+            LintIssue::new(
+                Severity::Warning,
+                "trailing-content",
+                "Prompt does not end with an empty Assistant section, the content after the last completion point is completed implicitly",
+            )
+            .at(last.line),
+        );
+    }
+
+    let placeholders = find_placeholders(body.lines());
+    let declared = front_matter
+        .inputs
+        .keys()
+        .map(|name| format!("{{{name}}}"))
+        .collect::<BTreeSet<_>>();
+    // Without inputs the values may come from the caller, a partial declaration is likely a typo
+    let undeclared_severity = match declared.is_empty() {
+        true => Severity::Warning,
+        false => Severity::Error,
+    };
+    let mut reported = BTreeSet::new();
+    for placeholder in &placeholders {
+        if !declared.contains(&placeholder.name) && reported.insert(&placeholder.name) {
+            issues.push(
+                LintIssue::new(
+                    undeclared_severity,
+                    "unresolved-placeholder",
+                    format!("Placeholder {} is not declared in inputs", placeholder.name),
+                )
+                .at(body_offset + placeholder.line),
+            );
+        }
+    }
+    for name in &declared {
+        if !placeholders
+            .iter()
+            .any(|placeholder| &placeholder.name == name)
+        {
+            issues.push(LintIssue::new(
+                Severity::Warning,
+                "unused-input",
+                format!("Input {name} is not used in the prompt"),
+            ));
+        }
+    }
+
+    let tokens = count_tokens(body);
+    if tokens > model.context_window() {
+        issues.push(LintIssue::new(
+            Severity::Error,
+            "context-window",
+            format!(
+                "Prompt has about {tokens} tokens which exceeds {} context window of {model}",
+                model.context_window()
+            ),
+        ));
+    }
+
+    issues.sort_by_key(|issue| issue.line.unwrap_or_default());
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint_markdown_prompt, Severity};
+    use crate::llm::chat_gpt::ChatGptModel;
+
+    fn codes(markdown: &str, model: &ChatGptModel) -> Vec<&'static str> {
+        lint_markdown_prompt(markdown, model)
+            .into_iter()
+            .map(|issue| issue.code)
+            .collect()
+    }
+
+    #[test]
+    fn lints_markdown() {
+        let markdown = r#"---
+inputs:
+  document: {}
+  audience: {}
+---
+# System
+Act like a teacher
+
+# User
+Summarize {document} for {reader}
+
+# Assistant
+"#;
+        let issues = lint_markdown_prompt(markdown, &ChatGptModel::default());
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].code, "unused-input");
+        assert_eq!(issues[1].code, "unresolved-placeholder");
+        assert_eq!(issues[1].severity, Severity::Error);
+        assert_eq!(issues[1].line, Some(10));
+
+        let markdown = "# User\nHi\n# User\nHello\n";
+        assert_eq!(
+            codes(markdown, &ChatGptModel::default()),
+            vec!["missing-system", "consecutive-role", "trailing-content"]
+        );
+
+        let markdown = "# System\nAct like a teacher\n# User\nHi\n# Assistant\n";
+        assert_eq!(codes(markdown, &ChatGptModel::O1Mini), vec!["o1-system"]);
+        assert!(codes(markdown, &ChatGptModel::default()).is_empty());
+
+        let markdown = format!(
+            "---\nmodel: gpt-4\n---\n# System\nAct like a teacher\n# User\n{{topic}} {}\n# Assistant\n",
+            "word ".repeat(9000)
+        );
+        let issues = lint_markdown_prompt(&markdown, &ChatGptModel::default());
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].code, "context-window");
+        assert_eq!(issues[1].code, "unresolved-placeholder");
+        assert_eq!(issues[1].severity, Severity::Warning);
+    }
+}
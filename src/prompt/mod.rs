@@ -0,0 +1,772 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use promptpunch_markdown::{
+    escape_placeholders, escape_role_header, parse_include, parse_role_header,
+    replace_placeholders, unescape_role_header,
+};
+
+use crate::{Completion, OutputFormat, Prompt, PromptMessage, PromptMessageRequest, Role};
+
+pub use promptpunch_markdown::{
+    find_placeholders, split_front_matter, FrontMatter, InputSpec, InputType, Placeholder,
+};
+
+pub mod formats;
+pub mod lint;
+pub mod registry;
+
+#[derive(Debug, Clone)]
+pub struct InjectableData {
+    placeholder: String,
+    content: String,
+}
+
+impl InjectableData {
+    pub fn new(placeholder: impl Display, content: impl Display) -> Self {
+        Self {
+            placeholder: placeholder.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    pub fn from_file(placeholder: impl Display, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self::new(placeholder, content))
+    }
+
+    pub fn from_stdin(placeholder: impl Display) -> anyhow::Result<Self> {
+        let content = std::io::read_to_string(std::io::stdin()).context("Failed to read stdin")?;
+        Ok(Self::new(placeholder, content))
+    }
+
+    /// Concatenates all the files matching the pattern,
+    /// each file is preceded by a `==> path <==` header
+    pub fn from_glob(placeholder: impl Display, pattern: &str) -> anyhow::Result<Self> {
+        let mut content = String::new();
+        for path in glob::glob(pattern)? {
+            let path = path?;
+            if !path.is_file() {
+                continue;
+            }
+            let file = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            if !content.is_empty() {
+                content.push('\n');
+            }
+            content += &format!("==> {} <==\n{}", path.display(), file);
+            if !content.ends_with('\n') {
+                content.push('\n');
+            }
+        }
+        if content.is_empty() {
+            anyhow::bail!("No files match {pattern}");
+        }
+        Ok(Self::new(placeholder, content))
+    }
+
+    pub async fn from_url(placeholder: impl Display, url: &str) -> anyhow::Result<Self> {
+        let response = reqwest::get(url)
+            .await
+            .with_context(|| format!("Failed to fetch {url}"))?
+            .error_for_status()?;
+        Ok(Self::new(placeholder, response.text().await?))
+    }
+
+    /// Builds data from a CLI-like value:
+    /// `@-` reads stdin, `@http(s)://..` fetches URL, `@pattern*` concatenates files,
+    /// `@path` reads a file, `@@value` escapes the literal `@value`
+    pub async fn from_source(placeholder: impl Display, source: &str) -> anyhow::Result<Self> {
+        let Some(source) = source.strip_prefix('@') else {
+            return Ok(Self::new(placeholder, source));
+        };
+        if source.starts_with('@') {
+            Ok(Self::new(placeholder, source))
+        } else if source == "-" {
+            Self::from_stdin(placeholder)
+        } else if source.starts_with("http://") || source.starts_with("https://") {
+            Self::from_url(placeholder, source).await
+        } else if source.contains(['*', '?', '[']) {
+            Self::from_glob(placeholder, source)
+        } else {
+            Self::from_file(placeholder, source)
+        }
+    }
+}
+
+/// Typed prompt input, see `#[derive(PromptInput)]` with the `macros` feature
+pub trait PromptInput {
+    /// Placeholders filled by the input, including braces
+    fn placeholders() -> Vec<String>;
+
+    fn injectable_data(&self) -> anyhow::Result<Vec<InjectableData>>;
+
+    /// Checks that every input placeholder is used in the markdown prompt
+    fn validate(markdown: &str) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        let (_, body) = split_front_matter(markdown)?;
+        let used = find_placeholders(body.lines())
+            .into_iter()
+            .map(|placeholder| placeholder.name)
+            .collect::<BTreeSet<_>>();
+        let unused = Self::placeholders()
+            .into_iter()
+            .filter(|placeholder| !used.contains(placeholder))
+            .collect::<Vec<_>>();
+        if !unused.is_empty() {
+            anyhow::bail!(
+                "Input placeholders are not used in the prompt: {}",
+                unused.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Parses markdown prompt with the input injected
+    fn render(&self, markdown: &str) -> anyhow::Result<Prompt>
+    where
+        Self: Sized,
+    {
+        Self::validate(markdown)?;
+        parse_markdown_prompt(markdown, &self.injectable_data()?)
+    }
+}
+
+#[cfg(feature = "macros")]
+pub use promptpunch_macros::PromptInput;
+
+/// Checks that every placeholder declared in the prompt is supplied
+/// and every supplied placeholder is used in the prompt
+pub fn validate_placeholders(
+    lines: impl IntoIterator<Item = impl AsRef<str>>,
+    injectable_data: &[InjectableData],
+) -> anyhow::Result<()> {
+    let lines = lines
+        .into_iter()
+        .map(|line| line.as_ref().to_string())
+        .collect::<Vec<_>>();
+    let declared = find_placeholders(&lines);
+
+    let missing = declared
+        .iter()
+        .filter(|placeholder| {
+            !injectable_data
+                .iter()
+                .any(|data| data.placeholder == placeholder.name)
+        })
+        .map(|placeholder| {
+            format!(
+                "{} (line {}, column {})",
+                placeholder.name, placeholder.line, placeholder.column
+            )
+        })
+        .collect::<Vec<_>>();
+    let unused = injectable_data
+        .iter()
+        .filter(|data| {
+            !declared
+                .iter()
+                .any(|placeholder| placeholder.name == data.placeholder)
+        })
+        .map(|data| data.placeholder.as_str())
+        .collect::<BTreeSet<_>>();
+
+    let mut errors = vec![];
+    if !missing.is_empty() {
+        errors.push(format!("missing values for {}", missing.join(", ")));
+    }
+    if !unused.is_empty() {
+        errors.push(format!(
+            "unused values for {}",
+            unused.into_iter().collect::<Vec<_>>().join(", ")
+        ));
+    }
+    if !errors.is_empty() {
+        anyhow::bail!("Invalid prompt placeholders: {}", errors.join("; "));
+    }
+    Ok(())
+}
+
+/// Checks supplied values against the input types declared in the front matter
+/// and appends defaults for the inputs used by the body lines that were not supplied
+pub fn resolve_inputs(
+    front_matter: &FrontMatter,
+    lines: impl IntoIterator<Item = impl AsRef<str>>,
+    injectable_data: &[InjectableData],
+) -> anyhow::Result<Vec<InjectableData>> {
+    let used = find_placeholders(lines)
+        .into_iter()
+        .map(|placeholder| placeholder.name)
+        .collect::<BTreeSet<_>>();
+    let mut resolved = injectable_data.to_vec();
+    for (name, spec) in &front_matter.inputs {
+        let placeholder = format!("{{{name}}}");
+        match injectable_data
+            .iter()
+            .find(|data| data.placeholder == placeholder)
+        {
+            Some(data) => spec
+                .kind
+                .check(&data.content)
+                .with_context(|| format!("Input {name} is expected to be {:?}", spec.kind))?,
+            None => {
+                if let Some(default) = spec
+                    .default
+                    .as_ref()
+                    .filter(|_| used.contains(&placeholder))
+                {
+                    resolved.push(InjectableData::new(placeholder, default));
+                }
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Builds prompt with the parameters declared in the front matter.
+/// The order of the messages is not checked unlike [`crate::PromptBuilder::build`],
+/// it's up to [`lint::lint_markdown_prompt`].
+pub fn front_matter_prompt(
+    front_matter: FrontMatter,
+    messages: Vec<PromptMessageRequest>,
+) -> anyhow::Result<Prompt> {
+    Ok(Prompt {
+        messages,
+        temperature: front_matter
+            .temperature
+            .unwrap_or_else(crate::default_temperature),
+        model: front_matter.model,
+        output_format: front_matter.output.unwrap_or_default(),
+        timeout: None,
+    })
+}
+
+/// Expands `{% include "shared/persona.md" %}` lines with the content of the referenced file.
+/// Paths are resolved relative to the including file first and then against the search path.
+/// Front matter of included files is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct IncludeResolver {
+    search_path: Vec<PathBuf>,
+}
+
+impl IncludeResolver {
+    pub fn new(search_path: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        Self {
+            search_path: search_path.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Search path taken from `PROMPTPUNCH_PATH` in the `PATH` format
+    pub fn from_env() -> Self {
+        let search_path = std::env::var_os("PROMPTPUNCH_PATH")
+            .map(|paths| std::env::split_paths(&paths).collect())
+            .unwrap_or_default();
+        Self { search_path }
+    }
+
+    pub fn with_search_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.search_path.push(path.into());
+        self
+    }
+
+    /// Reads prompt file with all the includes expanded
+    pub fn read(&self, path: impl AsRef<Path>) -> anyhow::Result<String> {
+        let path = path.as_ref();
+        let markdown = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read prompt from {}", path.display()))?;
+        let mut stack = vec![canonical(path)];
+        self.expand_inner(&markdown, path.parent(), &mut stack)
+    }
+
+    /// Expands includes in the markdown, relative includes are resolved against `base_dir`
+    pub fn expand(&self, markdown: &str, base_dir: Option<&Path>) -> anyhow::Result<String> {
+        self.expand_inner(markdown, base_dir, &mut vec![])
+    }
+
+    fn expand_inner(
+        &self,
+        markdown: &str,
+        base_dir: Option<&Path>,
+        stack: &mut Vec<PathBuf>,
+    ) -> anyhow::Result<String> {
+        let mut expanded = String::new();
+        for line in markdown.split_inclusive('\n') {
+            let Some(include) = parse_include(line) else {
+                expanded += line;
+                continue;
+            };
+            let path = self.resolve(include, base_dir)?;
+            let key = canonical(&path);
+            if stack.contains(&key) {
+                let cycle = stack
+                    .iter()
+                    .chain([&key])
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>();
+                anyhow::bail!("Prompt include cycle: {}", cycle.join(" -> "));
+            }
+
+            let markdown = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read included prompt {}", path.display()))?;
+            let (_, body) = split_front_matter(&markdown)?;
+            stack.push(key);
+            let body = self.expand_inner(body, path.parent(), stack)?;
+            stack.pop();
+
+            expanded += &body;
+            if line.ends_with('\n') && !body.ends_with('\n') {
+                expanded.push('\n');
+            }
+        }
+        Ok(expanded)
+    }
+
+    fn resolve(&self, include: &str, base_dir: Option<&Path>) -> anyhow::Result<PathBuf> {
+        let include = Path::new(include);
+        if include.is_absolute() {
+            return Ok(include.to_path_buf());
+        }
+        base_dir
+            .into_iter()
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(include))
+            .find(|path| path.is_file())
+            .with_context(|| format!("Included prompt {} not found", include.display()))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Reads markdown prompt with includes resolved via [`IncludeResolver::from_env`]
+pub fn read_markdown_prompt_from_file(
+    path: impl AsRef<Path>,
+    injectable_data: &[InjectableData],
+) -> anyhow::Result<Prompt> {
+    let markdown = IncludeResolver::from_env().read(path)?;
+    parse_markdown_prompt(&markdown, injectable_data)
+}
+
+/// Parses markdown prompt with optional front matter,
+/// placeholders are validated against the supplied data and front matter defaults,
+/// literal text such as `{word}` is escaped as `{{word}}`
+pub fn parse_markdown_prompt(
+    markdown: &str,
+    injectable_data: &[InjectableData],
+) -> anyhow::Result<Prompt> {
+    let (front_matter, body) = split_front_matter(markdown)?;
+    let injectable_data = resolve_inputs(&front_matter, body.lines(), injectable_data)?;
+    validate_placeholders(body.lines(), &injectable_data)?;
+    let messages = read_markdown_prompt(body.lines(), &injectable_data)?;
+    front_matter_prompt(front_matter, messages)
+}
+
+/// Parses markdown prompt body.
+/// Every `# System`, `# User` or `# Assistant` header starts a new message,
+/// other lines (including other headers) are the message content,
+/// a header escaped as `\# User` is content with one backslash removed.
+/// Empty `# Assistant` section waits for the completion,
+/// non-empty one is an assistant message, e.g. from a saved transcript.
+///
+/// Unlike 0.2.10 and earlier, line breaks of the content are kept instead of joining the lines,
+/// the last section is a message too instead of being dropped,
+/// and text before the first header is an error instead of a part of the first message.
+pub fn read_markdown_prompt(
+    lines: impl IntoIterator<Item = impl AsRef<str>>,
+    injectable_data: &[InjectableData],
+) -> anyhow::Result<Vec<PromptMessageRequest>> {
+    let mut messages = vec![];
+    let mut role = None;
+    let mut content = String::new();
+
+    for (idx, line) in lines.into_iter().enumerate() {
+        let line = line.as_ref();
+        if let Some(header_role) = parse_role_header(line) {
+            if let Some(role) = role.replace(header_role) {
+                messages.push(section_request(role, &content));
+            }
+            content = String::new();
+        } else {
+            if role.is_none() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                anyhow::bail!(
+                    "Prompt content on line {} precedes any role header",
+                    idx + 1
+                );
+            }
+            content += &inject(unescape_role_header(line), injectable_data);
+            content.push('\n');
+        }
+    }
+    if let Some(role) = role {
+        if role != Role::Assistant || !content.trim().is_empty() {
+            messages.push(section_request(role, &content));
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Replaces all the placeholders in the text with their content,
+/// escaped `{{placeholder}}` is kept as literal `{placeholder}`
+pub fn inject(text: &str, injectable_data: &[InjectableData]) -> String {
+    replace_placeholders(text, |placeholder| {
+        injectable_data
+            .iter()
+            .find(|data| data.placeholder == placeholder)
+            .map(|data| data.content.clone())
+    })
+}
+
+fn section_request(role: Role, content: &str) -> PromptMessageRequest {
+    let content = content.trim_matches(|c| c == '\n' || c == '\r');
+    if role == Role::Assistant && content.trim().is_empty() {
+        return PromptMessageRequest::WaitCompletion;
+    }
+    PromptMessageRequest::Message {
+        body: PromptMessage {
+            role,
+            content: content.to_string(),
+        },
+    }
+}
+
+/// Renders prompt in the markdown format understood by [`parse_markdown_prompt`],
+/// per completion parameters can't be expressed in markdown and are dropped
+pub fn write_markdown_prompt(prompt: &Prompt) -> String {
+    let front_matter = FrontMatter {
+        model: prompt.model.clone(),
+        temperature: Some(prompt.temperature),
+        output: (prompt.output_format == OutputFormat::Json).then_some(OutputFormat::Json),
+        ..Default::default()
+    };
+    let front_matter =
+        serde_yaml::to_string(&front_matter).expect("Front matter is always serializable");
+    let sections = prompt
+        .messages
+        .iter()
+        .map(|request| match request {
+            PromptMessageRequest::Message { body } => write_markdown_section(body),
+            PromptMessageRequest::WaitCompletion
+            | PromptMessageRequest::WaitCompletionWith { .. } => "# Assistant\n".to_string(),
+        })
+        .collect::<Vec<_>>();
+    format!("---\n{front_matter}---\n{}", sections.join("\n"))
+}
+
+/// Renders completed conversation with the assistant responses filled in
+pub fn write_markdown_transcript(completion: &Completion) -> String {
+    completion
+        .messages
+        .iter()
+        .map(write_markdown_section)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn write_markdown_section(message: &PromptMessage) -> String {
+    let header = match message.role {
+        Role::System => "System",
+        Role::User => "User",
+        Role::Assistant => "Assistant",
+    };
+    let content = message
+        .content
+        .split('\n')
+        .map(|line| escape_role_header(&escape_placeholders(line)))
+        .collect::<Vec<_>>();
+    format!("# {header}\n{}\n", content.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{prelude::*, test_util::TempDir, Completion};
+
+    use super::{
+        find_placeholders, parse_markdown_prompt, read_markdown_prompt, validate_placeholders,
+        write_markdown_prompt, write_markdown_transcript, IncludeResolver, InjectableData,
+        Placeholder,
+    };
+
+    #[test]
+    fn parses_markdown() {
+        let markdown = r#"
+# System
+System prompt
+
+# User
+Some user prompt
+
+# Assistant
+# User
+Another user prompt
+"#;
+        let got = read_markdown_prompt(markdown.lines(), &[]).unwrap();
+        let expected = vec![
+            message::system!("System prompt"),
+            message::user!("Some user prompt"),
+            message::complete!(),
+            message::user!("Another user prompt"),
+        ];
+        for (left, right) in got.into_iter().zip(expected) {
+            assert_eq!(left, right);
+        }
+    }
+
+    #[test]
+    fn parses_markdown_sections_strictly() {
+        let markdown = "# User\nFirst line\nSecond line\n# Assistant\n# User\nLast";
+        let got = read_markdown_prompt(markdown.lines(), &[]).unwrap();
+        let expected = vec![
+            message::user!("First line\nSecond line"),
+            message::complete!(),
+            message::user!("Last"),
+        ];
+        assert_eq!(got, expected);
+
+        let err = read_markdown_prompt("Preamble\n# User\nHi".lines(), &[]).unwrap_err();
+        assert!(err.to_string().contains("line 1 precedes any role header"));
+    }
+
+    #[test]
+    fn parses_markdown_without_checking_order() {
+        let markdown = "# Assistant\n# User\nHi\n# Assistant\n# Assistant\n# User\nBye\n";
+        let prompt = parse_markdown_prompt(markdown, &[]).unwrap();
+        assert_eq!(
+            prompt.messages,
+            vec![
+                message::complete!(),
+                message::user!("Hi"),
+                message::complete!(),
+                message::complete!(),
+                message::user!("Bye"),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_markdown() {
+        let prompt = PromptBuilder::default()
+            .messages(vec![
+                message::system!("System prompt"),
+                message::user!("First line\n\n## Not a role header\n# User\n\\# Assistant {x}"),
+                message::complete!(),
+                message::user!("Another user prompt"),
+                message::complete!(),
+            ])
+            .model(Some("yes: no".to_string()))
+            .build()
+            .unwrap();
+        let markdown = write_markdown_prompt(&prompt);
+        let parsed = parse_markdown_prompt(&markdown, &[]).unwrap();
+        assert_eq!(parsed.messages, prompt.messages[..4]);
+        assert_eq!(parsed.model, prompt.model);
+        assert_eq!(parsed.temperature, prompt.temperature);
+
+        let completion = Completion {
+            messages: vec![
+                PromptMessage {
+                    role: Role::User,
+                    content: "Hi".to_string(),
+                },
+                PromptMessage {
+                    role: Role::Assistant,
+                    content: "# Hello\nthere".to_string(),
+                },
+            ],
+            user_tokens: 1,
+            assistant_tokens: 3,
+            served_by: Vec::new(),
+        };
+        let markdown = write_markdown_transcript(&completion);
+        let parsed = read_markdown_prompt(markdown.lines(), &[]).unwrap();
+        let expected = completion
+            .messages
+            .iter()
+            .cloned()
+            .map(|body| PromptMessageRequest::Message { body })
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, expected);
+
+        let json = serde_json::to_string(&prompt).unwrap();
+        let deserialized = serde_json::from_str::<Prompt>(&json).unwrap();
+        assert_eq!(deserialized.messages, prompt.messages);
+    }
+
+    #[test]
+    fn finds_placeholders() {
+        let markdown = r#"# User
+Summarize {document} for {audience}
+Answer as JSON {"summary": "..."} with {audience}
+Keep {{literal}} text
+"#;
+        let got = find_placeholders(markdown.lines());
+        let expected = vec![
+            Placeholder {
+                name: "{document}".to_string(),
+                line: 2,
+                column: 11,
+            },
+            Placeholder {
+                name: "{audience}".to_string(),
+                line: 2,
+                column: 26,
+            },
+            Placeholder {
+                name: "{audience}".to_string(),
+                line: 3,
+                column: 40,
+            },
+        ];
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn validates_placeholders() {
+        let markdown = "# User\nSummarize {document} for {audience}";
+        let data = [
+            InjectableData::new("{document}", "text"),
+            InjectableData::new("{audience}", "kids"),
+        ];
+        assert!(validate_placeholders(markdown.lines(), &data).is_ok());
+
+        let data = [
+            InjectableData::new("{document}", "text"),
+            InjectableData::new("{audiense}", "kids"),
+        ];
+        let err = validate_placeholders(markdown.lines(), &data)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("missing values for {audience} (line 2, column 26)"));
+        assert!(err.contains("unused values for {audiense}"));
+
+        let markdown = "# User\nFill {{word}} in {\"word\": {{word}}} for {audience}";
+        let data = [InjectableData::new("{audience}", "kids")];
+        let prompt = parse_markdown_prompt(markdown, &data).unwrap();
+        assert_eq!(
+            prompt.messages,
+            [message::user!("Fill {word} in {\"word\": {word}} for kids")]
+        );
+        let data = [
+            InjectableData::new("{audience}", "kids"),
+            InjectableData::new("{word}", "text"),
+        ];
+        let err = parse_markdown_prompt(markdown, &data).unwrap_err();
+        assert!(err.to_string().contains("unused values for {word}"));
+    }
+
+    #[test]
+    fn parses_front_matter() {
+        let markdown = r#"---
+model: gpt-4o-mini
+temperature: 0.5
+inputs:
+  document:
+    description: Text to summarize
+  words:
+    type: integer
+    default: 50
+output: json
+---
+# User
+Summarize {document} in {words} words as JSON
+# Assistant
+"#;
+        let data = [InjectableData::new("{document}", "text")];
+        let prompt = parse_markdown_prompt(markdown, &data).unwrap();
+        assert_eq!(prompt.model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(prompt.temperature, 0.5);
+        assert_eq!(prompt.output_format, OutputFormat::Json);
+        assert_eq!(
+            prompt.messages,
+            vec![message::user!("Summarize text in 50 words as JSON")]
+        );
+
+        let data = [
+            InjectableData::new("{document}", "text"),
+            InjectableData::new("{words}", "many"),
+        ];
+        assert!(parse_markdown_prompt(markdown, &data).is_err());
+
+        // Defaults of the inputs the body does not use are not injected
+        let markdown = markdown.replace(" in {words} words", "");
+        let data = [InjectableData::new("{document}", "text")];
+        let prompt = parse_markdown_prompt(&markdown, &data).unwrap();
+        assert_eq!(
+            prompt.messages,
+            vec![message::user!("Summarize text as JSON")]
+        );
+    }
+
+    #[test]
+    fn expands_includes() {
+        let dir = TempDir::new("include");
+        let shared = dir.join("shared");
+        std::fs::create_dir_all(&shared).unwrap();
+        std::fs::write(shared.join("persona.md"), "You are a {role}\n").unwrap();
+        std::fs::write(
+            dir.join("prompt.md"),
+            "# System\n{% include \"shared/persona.md\" %}\n# User\nHi\n# Assistant\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("a.md"), "{% include \"b.md\" %}\n").unwrap();
+        std::fs::write(dir.join("b.md"), "{% include \"a.md\" %}\n").unwrap();
+        std::fs::write(dir.join("searched.md"), "{% include \"persona.md\" %}\n").unwrap();
+
+        let resolver = IncludeResolver::default();
+        let markdown = resolver.read(dir.join("prompt.md")).unwrap();
+        assert_eq!(
+            markdown,
+            "# System\nYou are a {role}\n# User\nHi\n# Assistant\n"
+        );
+
+        let err = resolver.read(dir.join("a.md")).unwrap_err().to_string();
+        assert!(err.contains("Prompt include cycle"));
+
+        assert!(resolver.read(dir.join("searched.md")).is_err());
+        let resolver = IncludeResolver::new([&shared]);
+        assert_eq!(
+            resolver.read(dir.join("searched.md")).unwrap(),
+            "You are a {role}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_injectable_data_from_files() {
+        let dir = TempDir::new("data");
+        std::fs::write(dir.join("a.txt"), "first").unwrap();
+        std::fs::write(dir.join("b.txt"), "second\n").unwrap();
+
+        let data =
+            InjectableData::from_source("{doc}", &format!("@{}", dir.join("a.txt").display()))
+                .await
+                .unwrap();
+        assert_eq!(data.content, "first");
+
+        let pattern = format!("@{}/*.txt", dir.path().display());
+        let data = InjectableData::from_source("{doc}", &pattern)
+            .await
+            .unwrap();
+        assert_eq!(
+            data.content,
+            format!(
+                "==> {0}/a.txt <==\nfirst\n\n==> {0}/b.txt <==\nsecond\n",
+                dir.path().display()
+            )
+        );
+
+        let data = InjectableData::from_source("{doc}", "@@handle")
+            .await
+            .unwrap();
+        assert_eq!(data.content, "@handle");
+    }
+}
@@ -0,0 +1,274 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use super::{formats::PromptFormat, split_front_matter, IncludeResolver, InjectableData};
+use crate::Prompt;
+
+/// Prompt version, `v3` and `3` are equal and `v10` is newer than `v9`
+#[derive(Debug, Clone, Eq)]
+pub struct Version(String);
+
+impl Version {
+    fn components(&self) -> Vec<Result<u64, &str>> {
+        self.0
+            .trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse::<u64>().map_err(|_| part))
+            .collect()
+    }
+
+    fn looks_like_version(value: &str) -> bool {
+        let value = value.strip_prefix('v').unwrap_or(value);
+        !value.is_empty()
+            && value
+                .split('.')
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.components().cmp(&other.components())
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegistryEntry {
+    pub name: String,
+    pub version: Option<Version>,
+    pub path: PathBuf,
+    pub format: PromptFormat,
+    /// Prompt text with includes expanded
+    text: String,
+}
+
+impl RegistryEntry {
+    pub fn render(&self, injectable_data: &[InjectableData]) -> anyhow::Result<Prompt> {
+        self.format
+            .parse(&self.text, injectable_data)
+            .with_context(|| format!("Failed to render prompt {}", self.path.display()))
+    }
+}
+
+/// Name and version declared inside YAML or TOML prompt document
+#[derive(Debug, Default, Deserialize)]
+struct DocumentIdentity {
+    name: Option<String>,
+    version: Option<String>,
+}
+
+/// Named and versioned prompts loaded from a directory tree.
+///
+/// Name and version come from the front matter or from the path relative to the root:
+/// `summarize@v3.md` and `summarize/v3.md` are both `summarize@v3`,
+/// `team/summarize.md` is unversioned `team/summarize`.
+#[derive(Debug, Clone, Default)]
+pub struct PromptRegistry {
+    prompts: BTreeMap<String, BTreeMap<Option<Version>, RegistryEntry>>,
+}
+
+impl PromptRegistry {
+    pub fn load(root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let root = root.as_ref();
+        let resolver = IncludeResolver::default().with_search_path(root);
+        let mut registry = Self::default();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read prompts from {}", dir.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Some(format) = PromptFormat::detect(&path) {
+                    registry.insert(root, path, format, &resolver)?;
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    fn insert(
+        &mut self,
+        root: &Path,
+        path: PathBuf,
+        format: PromptFormat,
+        resolver: &IncludeResolver,
+    ) -> anyhow::Result<()> {
+        let text = match format {
+            PromptFormat::Markdown => resolver.read(&path)?,
+            _ => std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read prompt from {}", path.display()))?,
+        };
+        let identity = match format {
+            PromptFormat::Markdown => {
+                let (front_matter, _) = split_front_matter(&text)
+                    .with_context(|| format!("Invalid prompt {}", path.display()))?;
+                DocumentIdentity {
+                    name: front_matter.name,
+                    version: front_matter.version,
+                }
+            }
+            PromptFormat::Yaml => serde_yaml::from_str(&text)
+                .with_context(|| format!("Invalid prompt {}", path.display()))?,
+            PromptFormat::Toml => toml::from_str(&text)
+                .with_context(|| format!("Invalid prompt {}", path.display()))?,
+            PromptFormat::OpenAiJson => DocumentIdentity::default(),
+        };
+
+        let (path_name, path_version) = identity_from_path(root, &path);
+        let name = identity.name.unwrap_or(path_name);
+        let version = identity.version.or(path_version).map(Version);
+
+        let versions = self.prompts.entry(name.clone()).or_default();
+        if let Some(existing) = versions.get(&version) {
+            anyhow::bail!(
+                "Prompt {} is declared by both {} and {}",
+                qualified_name(&name, version.as_ref()),
+                existing.path.display(),
+                path.display()
+            );
+        }
+        versions.insert(
+            version.clone(),
+            RegistryEntry {
+                name,
+                version,
+                path,
+                format,
+                text,
+            },
+        );
+        Ok(())
+    }
+
+    /// Looks up `name@version`, plain `name` resolves to the latest version
+    pub fn entry(&self, key: &str) -> anyhow::Result<&RegistryEntry> {
+        let (name, version) = match key.split_once('@') {
+            Some((name, version)) => (name, Some(Version(version.to_string()))),
+            None => (key, None),
+        };
+        let versions = self
+            .prompts
+            .get(name)
+            .with_context(|| format!("Prompt {name} is not found"))?;
+        let entry = match version {
+            Some(version) => versions.get(&Some(version)),
+            None => versions.values().next_back(),
+        };
+        entry.with_context(|| format!("Prompt {key} is not found"))
+    }
+
+    /// Renders prompt `name@version` or the latest version of `name`
+    pub fn get(&self, key: &str, injectable_data: &[InjectableData]) -> anyhow::Result<Prompt> {
+        self.entry(key)?.render(injectable_data)
+    }
+
+    /// All the prompts ordered by name and version
+    pub fn entries(&self) -> impl Iterator<Item = &RegistryEntry> {
+        self.prompts.values().flat_map(|versions| versions.values())
+    }
+}
+
+fn qualified_name(name: &str, version: Option<&Version>) -> String {
+    match version {
+        Some(version) => format!("{name}@{version}"),
+        None => name.to_string(),
+    }
+}
+
+fn identity_from_path(root: &Path, path: &Path) -> (String, Option<String>) {
+    let relative = path.strip_prefix(root).unwrap_or(path).with_extension("");
+    let parts = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let (stem, parents) = parts.split_last().expect("Prompt path is not empty");
+
+    if let Some((name, version)) = stem.split_once('@') {
+        let name = parents
+            .iter()
+            .map(String::as_str)
+            .chain([name])
+            .collect::<Vec<_>>();
+        return (name.join("/"), Some(version.to_string()));
+    }
+    if Version::looks_like_version(stem) && !parents.is_empty() {
+        return (parents.join("/"), Some(stem.clone()));
+    }
+    (parts.join("/"), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PromptRegistry;
+    use crate::{prelude::*, test_util::TempDir};
+
+    #[test]
+    fn loads_versioned_prompts() {
+        let dir = TempDir::new("registry");
+        std::fs::create_dir_all(dir.join("summarize")).unwrap();
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("shared/persona.md"), "Act like a teacher\n").unwrap();
+        std::fs::write(
+            dir.join("summarize/v2.md"),
+            "# System\n{% include \"shared/persona.md\" %}\n# User\nSummarize {document}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("summarize/v10.md"),
+            "# User\nBriefly summarize {document}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("legacy.md"),
+            "---\nname: summarize\nversion: v1\n---\n# User\nTL;DR {document}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "Not a prompt").unwrap();
+
+        let registry = PromptRegistry::load(dir.path()).unwrap();
+        let data = [InjectableData::new("{document}", "text")];
+        assert_eq!(
+            registry.get("summarize", &data).unwrap().messages,
+            vec![message::user!("Briefly summarize text")]
+        );
+        assert_eq!(
+            registry.get("summarize@v2", &data).unwrap().messages,
+            vec![
+                message::system!("Act like a teacher"),
+                message::user!("Summarize text")
+            ]
+        );
+        assert_eq!(
+            registry.get("summarize@1", &data).unwrap().messages,
+            vec![message::user!("TL;DR text")]
+        );
+        assert!(registry.get("summarize@v3", &data).is_err());
+        assert!(registry.entry("shared/persona").is_ok());
+        assert_eq!(registry.entries().count(), 4);
+    }
+}
@@ -0,0 +1,208 @@
+//! Providers and helpers shared by the tests, the binary includes this file too
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use promptpunch::{
+    llm::error::{Interrupted, PartialCompletionError},
+    prelude::*,
+    Completion,
+};
+
+/// Completion of the messages without token usage
+pub fn completion(messages: Vec<PromptMessage>) -> Completion {
+    Completion {
+        messages,
+        user_tokens: 0,
+        assistant_tokens: 0,
+        served_by: Vec::new(),
+    }
+}
+
+pub fn assistant(content: impl Into<String>) -> PromptMessage {
+    PromptMessage {
+        role: Role::Assistant,
+        content: content.into(),
+    }
+}
+
+/// Messages of the prompt without the pending completions
+fn messages(prompt: &Prompt) -> Vec<PromptMessage> {
+    prompt
+        .messages
+        .iter()
+        .filter_map(|request| match request {
+            PromptMessageRequest::Message { body } => Some(body.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Directory under the system temp dir, removed on drop even when the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("promptpunch-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Failed to create temp dir");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Echoes the last message, fails when it contains `fail`
+#[derive(Default)]
+pub struct Echo {
+    pub calls: AtomicUsize,
+    /// Model of the last prompt
+    pub model: Mutex<Option<String>>,
+}
+
+#[async_trait]
+impl LlmProvider for Echo {
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        *self.model.lock().unwrap() = prompt.model.clone();
+        let mut messages = messages(prompt);
+        let last = messages.last().map(|message| message.content.clone());
+        let last = last.unwrap_or_default();
+        if last.contains("fail") {
+            anyhow::bail!("Failed on purpose");
+        }
+        messages.push(assistant(format!("echo {last}")));
+        Ok(completion(messages))
+    }
+}
+
+/// Replies with the number of the assistant message, the completions requested before
+/// the last one are replied with `pending`, counts a token per message of the whole history
+/// like real providers do
+#[derive(Default)]
+pub struct Counter {
+    pub requests: AtomicUsize,
+    /// Cache identity, e.g. to tell the models apart
+    pub model: &'static str,
+}
+
+#[async_trait]
+impl LlmProvider for Counter {
+    fn cache_identity(&self) -> String {
+        self.model.to_string()
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let mut messages = vec![];
+        let mut requests = prompt.messages.iter().peekable();
+        while let Some(request) = requests.next() {
+            match request {
+                PromptMessageRequest::Message { body } => messages.push(body.clone()),
+                _ if requests.peek().is_some() => messages.push(assistant("pending")),
+                _ => {}
+            }
+        }
+        let replies = messages
+            .iter()
+            .filter(|message| message.role == Role::Assistant)
+            .count();
+        messages.push(assistant(format!("reply {}", replies + 1)));
+        let assistant_tokens = replies + 1;
+        Ok(Completion {
+            user_tokens: messages.len() - assistant_tokens,
+            assistant_tokens,
+            ..completion(messages)
+        })
+    }
+}
+
+/// Fails every other request, echoes the last user message otherwise
+#[derive(Default)]
+pub struct Flaky {
+    pub requests: AtomicUsize,
+}
+
+#[async_trait]
+impl LlmProvider for Flaky {
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        let mut messages = vec![];
+        for (step, request) in prompt.messages.iter().enumerate() {
+            match request {
+                PromptMessageRequest::Message { body } => messages.push(body.clone()),
+                _ => {
+                    if self.requests.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+                        let error = anyhow::anyhow!("Flaky");
+                        let partial =
+                            PartialCompletionError::new(completion(messages), step, error);
+                        return Err(partial.into());
+                    }
+                    let last = messages.last().unwrap().content.clone();
+                    messages.push(assistant(format!("echo {last}")));
+                }
+            }
+        }
+        Ok(completion(messages))
+    }
+}
+
+/// Takes 100ms a request, times out like real providers do
+pub struct Slow;
+
+#[async_trait]
+impl LlmProvider for Slow {
+    fn default_model(&self) -> Option<String> {
+        Some("slow".to_string())
+    }
+
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        let delay = Duration::from_millis(100);
+        if let Some(timeout) = prompt.timeout.filter(|timeout| *timeout < delay) {
+            tokio::time::sleep(timeout).await;
+            return Err(Interrupted::TimedOut(timeout).into());
+        }
+        tokio::time::sleep(delay).await;
+        Ok(completion(vec![assistant("done")]))
+    }
+}
+
+/// Replies with its name, fails without one
+pub struct Named(pub Option<&'static str>);
+
+#[async_trait]
+impl LlmProvider for Named {
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        assert_eq!(prompt.model, None);
+        let name = self.0.ok_or_else(|| anyhow::anyhow!("Backend is down"))?;
+        Ok(completion(vec![assistant(name)]))
+    }
+}
+
+/// Fails its second request only, replies like [`Named`] otherwise
+#[derive(Default)]
+pub struct FailsSecond(AtomicUsize);
+
+#[async_trait]
+impl LlmProvider for FailsSecond {
+    async fn complete_chat(&self, prompt: &Prompt) -> anyhow::Result<Completion> {
+        if self.0.fetch_add(1, Ordering::SeqCst) == 1 {
+            anyhow::bail!("Backend is down");
+        }
+        Named(Some("reply")).complete_chat(prompt).await
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{
-    llm::{chat_gpt::ChatGpt, LlmProvider},
+    llm::LlmProvider,
     prelude::*,
-    prompt::InjectableData,
+    prompt::{find_placeholders, parse_markdown_prompt, split_front_matter, InjectableData},
 };
 use askama_axum::Template;
 use axum::{
@@ -11,19 +11,19 @@ use axum::{
     routing::{get, post},
     Router,
 };
-use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Display;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub llm: ChatGpt,
+    pub llm: Arc<dyn LlmProvider>,
     pub prompt_info: PromptInfo,
 }
 
 #[derive(Clone)]
 pub struct PromptInfo {
     pub prompt_markdown: String,
-    pub placeholder_name: String,
 }
 
 impl Default for PromptInfo {
@@ -33,11 +33,10 @@ impl Default for PromptInfo {
 Some system prompt
 
 # User
-Some user input that uses {placheloder_name}
+Some user input that uses {placeholder_name}
 
 # Assistant"#
                 .to_string(),
-            placeholder_name: "{placeholder_name}".to_string(),
         }
     }
 }
@@ -46,9 +45,30 @@ pub fn init_router(state: AppState) -> Router {
     Router::new()
         .route("/", get(get::root))
         .route("/", post(post::generate))
+        .route("/placeholders", post(post::placeholders))
         .with_state(state)
 }
 
+/// Form input of a placeholder used in the prompt
+struct PlaceholderField {
+    /// Including braces, also the form field name
+    name: String,
+    value: String,
+}
+
+fn placeholder_fields(markdown: &str, values: &HashMap<String, String>) -> Vec<PlaceholderField> {
+    let body = split_front_matter(markdown).map_or(markdown, |(_, body)| body);
+    let mut seen = BTreeSet::new();
+    find_placeholders(body.lines())
+        .into_iter()
+        .filter(|placeholder| seen.insert(placeholder.name.clone()))
+        .map(|placeholder| PlaceholderField {
+            value: values.get(&placeholder.name).cloned().unwrap_or_default(),
+            name: placeholder.name,
+        })
+        .collect()
+}
+
 mod get {
     use super::*;
 
@@ -56,11 +76,14 @@ mod get {
     #[template(path = "root.html")]
     struct Root {
         prompt_info: PromptInfo,
+        placeholders: Vec<PlaceholderField>,
     }
 
     pub async fn root(State(state): State<AppState>) -> impl IntoResponse {
+        let placeholders = placeholder_fields(&state.prompt_info.prompt_markdown, &HashMap::new());
         Root {
             prompt_info: state.prompt_info,
+            placeholders,
         }
     }
 }
@@ -68,12 +91,18 @@ mod get {
 mod post {
     use super::*;
 
-    #[derive(Debug, Deserialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct GenerateRequest {
-        prompt: String,
-        placeholder_name: String,
-        placeholder_value: String,
+    #[derive(Template)]
+    #[template(path = "placeholders.html")]
+    struct Placeholders {
+        placeholders: Vec<PlaceholderField>,
+    }
+
+    /// Fields of the edited prompt keeping the values typed so far
+    pub async fn placeholders(Form(form): Form<HashMap<String, String>>) -> impl IntoResponse {
+        let prompt = form.get("prompt").map(String::as_str).unwrap_or_default();
+        Placeholders {
+            placeholders: placeholder_fields(prompt, &form),
+        }
     }
 
     #[derive(Template)]
@@ -92,39 +121,28 @@ mod post {
 
     pub async fn generate(
         State(state): State<AppState>,
-        Form(req): Form<GenerateRequest>,
+        Form(mut form): Form<HashMap<String, String>>,
     ) -> impl IntoResponse {
-        tracing::info!("Got form request {req:?}");
-        let GenerateRequest {
-            prompt,
-            placeholder_name,
-            placeholder_value,
-        } = req;
-
-        if !prompt.contains(&placeholder_name) {
-            return (
-                StatusCode::BAD_REQUEST,
-                PromptPunchError::new(format!(
-                    "Placeholder {} not found in the prompt",
-                    placeholder_name
-                )),
-            )
-                .into_response();
-        }
-        let injectable_data = [InjectableData::new(placeholder_name, placeholder_value)];
-
-        let Ok(messages) = crate::prompt::read_markdown_prompt(prompt.lines(), &injectable_data)
-        else {
-            return (
-                StatusCode::BAD_REQUEST,
-                PromptPunchError::new("Failed to read prompt"),
-            )
-                .into_response();
+        tracing::info!("Got form request {form:?}");
+        let prompt = form.remove("prompt").unwrap_or_default();
+        // Empty fields are left to the front matter defaults
+        let injectable_data = form
+            .into_iter()
+            .filter(|(name, value)| name.starts_with('{') && !value.is_empty())
+            .map(|(name, value)| InjectableData::new(name, value))
+            .collect::<Vec<_>>();
+        let prompt = match parse_markdown_prompt(&prompt, &injectable_data) {
+            Ok(prompt) => prompt,
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    PromptPunchError::new(format!("Failed to read prompt: {err:#}")),
+                )
+                    .into_response();
+            }
         };
 
-        let prompt = PromptBuilder::default().messages(messages).build().unwrap();
-
-        let completion = match state.llm.complete_chat(prompt).await {
+        let completion = match state.llm.complete_chat(&prompt).await {
             Ok(r) => r,
             Err(err) => {
                 let msg = format!("Failed to get completion from LLM with {err:?}");